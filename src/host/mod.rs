@@ -11,6 +11,7 @@ use std::collections::HashMap;
 
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
+use std::task::Poll;
 use std::rc::Rc;
 
 use mio::net::TcpStream;
@@ -333,12 +334,14 @@ impl fmt::Debug for PeerStatus {
 
 
 struct PeerFileSystem {
-    cache: HashMap<u64, Arc<Mutex<PeerFile>>>
+    cache: HashMap<u64, Arc<PeerFile>>
 }
 
 impl PeerFileSystem {
 
-    pub fn open_file(&self) -> io::Result<MutexGuard<PeerFile>> {
+    /// Partial files can be read concurrently, so files are shared without
+    /// additional locking.
+    pub fn open_file(&self) -> io::Result<Arc<PeerFile>> {
         todo!()
     }
 
@@ -355,7 +358,7 @@ struct PeerFileFiller<'a> {
 }
 
 impl<'a> PartialFiller for PeerFileFiller<'a> {
-    fn provide<W: Write>(&self, block_index: u64, block_len: usize, dest: W) -> io::Result<()> {
+    fn provide<W: Write>(&self, block_index: u64, block_len: usize, dest: W) -> io::Result<Poll<()>> {
 
         todo!()
 
@@ -10,6 +10,8 @@ use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
 
+use crate::range::RangeVec;
+
 
 const ID_REJECTED: u8           = 0xF0;
 
@@ -66,31 +68,33 @@ pub enum Packet {
     /// The peer receiving this packet can keep the information that the requesting
     /// node need the missing ranges, when the requested peer get some missing blocks
     /// of this file, it can update all peers that have previously requested this
-    /// file using a FILE_HANDLE_UPDATE.
+    /// file using a FILE_HANDLE_UPDATE. Block ranges are varint-encoded. The block
+    /// length is fixed for the whole file, only the last block can be shorter.
     FileHandle {
         request_id: u64,
         handle: u64,
         block_count: u64,
-        block_ranges: Vec<(u64, u64)>
+        block_len: u32,
+        block_ranges: RangeVec<u64>
     },
-    /// FILE_HANDLE_UPDATE (handle: u64, block_count: u64, block_ranges: Vec<(u64, u64)>)
+    /// FILE_HANDLE_UPDATE (handle: u64, block_count: u64, block_ranges: RangeVec<u64>)
     /// A packet sent to peers to update a previously request file with new supported
     /// block ranges or block count. This packet should be ignored if the peer hasn't
     /// previously requested the file, it should know the handle.
     FileHandleUpdate {
         handle: u64,
         block_count: u64,
-        block_ranges: Vec<(u64, u64)>
+        block_ranges: RangeVec<u64>
     },
-    /// A request to get a block from a file handle. The block size is currently
-    /// assumed to be 4Kio. The request ID is used to follow the response.
+    /// A request to get a block from a file handle, the block length is given by
+    /// the FILE_HANDLE packet. The request ID is used to follow the response.
     FileBlockGet {
         request_id: u64,
         handle: u64,
         index: u64
     },
-    /// A response to FILE_BLOCK_GET with a block data, length is 4Kio if the block
-    /// is fully used, if length is less than 4Kio this should be the last block.
+    /// A response to FILE_BLOCK_GET with a block data, length is the file's block
+    /// length if the block is fully used, if shorter this should be the last block.
     FileBlockData {
         request_id: u64,
         data: Vec<u8>
@@ -139,6 +143,20 @@ impl Packet {
                 let path = read.read_str()?;
                 Ok(Packet::FileOpen { request_id, channel_handle, path })
             }
+            ID_FILE_HANDLE => {
+                let request_id = read.read_u64::<BE>()?;
+                let handle = read.read_u64::<BE>()?;
+                let block_count = read.read_u64::<BE>()?;
+                let block_len = read.read_u32::<BE>()?;
+                let block_ranges = RangeVec::read_varint(&mut read)?;
+                Ok(Packet::FileHandle { request_id, handle, block_count, block_len, block_ranges })
+            }
+            ID_FILE_HANDLE_UPDATE => {
+                let handle = read.read_u64::<BE>()?;
+                let block_count = read.read_u64::<BE>()?;
+                let block_ranges = RangeVec::read_varint(&mut read)?;
+                Ok(Packet::FileHandleUpdate { handle, block_count, block_ranges })
+            }
             _ => Err(ErrorKind::InvalidData.into())
         }
 
@@ -173,6 +191,20 @@ impl Packet {
                 write.write_u64::<BE>(*channel_handle)?;
                 write.write_str(path)?;
             }
+            Packet::FileHandle { request_id, handle, block_count, block_len, block_ranges } => {
+                write.write_u8(ID_FILE_HANDLE)?;
+                write.write_u64::<BE>(*request_id)?;
+                write.write_u64::<BE>(*handle)?;
+                write.write_u64::<BE>(*block_count)?;
+                write.write_u32::<BE>(*block_len)?;
+                block_ranges.write_varint(&mut write)?;
+            }
+            Packet::FileHandleUpdate { handle, block_count, block_ranges } => {
+                write.write_u8(ID_FILE_HANDLE_UPDATE)?;
+                write.write_u64::<BE>(*handle)?;
+                write.write_u64::<BE>(*block_count)?;
+                block_ranges.write_varint(&mut write)?;
+            }
             _ => unimplemented!()
         }
 
@@ -0,0 +1,399 @@
+//! Block sets, used to track filled blocks of partial files.
+
+use std::borrow::Cow;
+
+use crate::range::RangeVec;
+
+
+/// A set of block indices, different implementations are available
+/// depending on how blocks are expected to be filled.
+pub trait BlockSet {
+
+    /// Insert all blocks in the given range.
+    fn insert(&mut self, from: u64, to: u64);
+
+    /// Remove all blocks in the given range.
+    fn remove(&mut self, from: u64, to: u64);
+
+    /// Return true if the given block is in this set.
+    fn contains(&self, block: u64) -> bool;
+
+    /// Return the ranges of blocks in this set, this may need to build
+    /// them from the whole set.
+    fn ranges(&self) -> Cow<'_, RangeVec<u64>>;
+
+    /// Return the ranges of blocks missing from this set between `from` and
+    /// `to`, only this window is scanned.
+    fn gaps(&self, from: u64, to: u64) -> RangeVec<u64>;
+
+    /// Return the number of blocks in this set.
+    fn count(&self) -> u64;
+
+}
+
+/// Range vector are well suited for files filled sequentially, because
+/// they degrade to many small ranges when filled randomly.
+impl BlockSet for RangeVec<u64> {
+
+    #[inline]
+    fn insert(&mut self, from: u64, to: u64) {
+        self.push(from, to);
+    }
+
+    #[inline]
+    fn remove(&mut self, from: u64, to: u64) {
+        RangeVec::remove(self, from, to);
+    }
+
+    #[inline]
+    fn contains(&self, block: u64) -> bool {
+        RangeVec::contains(self, block)
+    }
+
+    #[inline]
+    fn ranges(&self) -> Cow<'_, RangeVec<u64>> {
+        Cow::Borrowed(self)
+    }
+
+    #[inline]
+    fn gaps(&self, from: u64, to: u64) -> RangeVec<u64> {
+        RangeVec::gaps(self, from, to).collect()
+    }
+
+    #[inline]
+    fn count(&self) -> u64 {
+        self.covered_len()
+    }
+
+}
+
+
+/// A plain bitmap of blocks, well suited for files filled randomly. Its
+/// memory usage only depends on the highest block index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockBitmap {
+    /// Words of 64 blocks, trailing words are never zero.
+    words: Vec<u64>,
+    /// Number of blocks set, updated on each change.
+    count: u64,
+}
+
+impl BlockBitmap {
+
+    pub fn new() -> Self {
+        Self { words: Vec::new(), count: 0 }
+    }
+
+    /// Internal function to call the given function with the index and
+    /// the mask of every word intersecting the given range.
+    fn for_each_word<F>(from: u64, to: u64, mut func: F)
+    where
+        F: FnMut(usize, u64)
+    {
+        let mut block = from;
+        while block < to {
+            let word_idx = block / 64;
+            let bit = block % 64;
+            let end = to.min((word_idx + 1) * 64);
+            let len = end - block;
+            let mask = if len == 64 { u64::MAX } else { ((1 << len) - 1) << bit };
+            func(word_idx as usize, mask);
+            block = end;
+        }
+    }
+
+}
+
+impl BlockSet for BlockBitmap {
+
+    fn insert(&mut self, from: u64, to: u64) {
+        assert!(to > from, "Invalid range.");
+        let words_len = ((to - 1) / 64 + 1) as usize;
+        if self.words.len() < words_len {
+            self.words.resize(words_len, 0);
+        }
+        Self::for_each_word(from, to, |word_idx, mask| {
+            let word = &mut self.words[word_idx];
+            self.count += (mask & !*word).count_ones() as u64;
+            *word |= mask;
+        });
+    }
+
+    fn remove(&mut self, from: u64, to: u64) {
+        assert!(to > from, "Invalid range.");
+        let to = to.min(self.words.len() as u64 * 64);
+        Self::for_each_word(from, to, |word_idx, mask| {
+            let word = &mut self.words[word_idx];
+            self.count -= (mask & *word).count_ones() as u64;
+            *word &= !mask;
+        });
+        while let Some(0) = self.words.last() {
+            self.words.pop();
+        }
+    }
+
+    fn contains(&self, block: u64) -> bool {
+        match self.words.get((block / 64) as usize) {
+            Some(&word) => (word >> (block % 64)) & 1 != 0,
+            None => false
+        }
+    }
+
+    fn ranges(&self) -> Cow<'_, RangeVec<u64>> {
+
+        let mut data = Vec::new();
+        // Start of the range being built, if any.
+        let mut start = None;
+
+        for (word_idx, &word) in self.words.iter().enumerate() {
+            let base = word_idx as u64 * 64;
+            let mut bit = 0;
+            while bit < 64 {
+                let rest = word >> bit;
+                match start {
+                    None => {
+                        if rest == 0 {
+                            break;
+                        }
+                        bit += rest.trailing_zeros();
+                        start = Some(base + bit as u64);
+                    }
+                    Some(from) => {
+                        // Zeros are shifted in, so this can't overflow the word.
+                        bit += rest.trailing_ones();
+                        if bit < 64 {
+                            data.push((from, base + bit as u64));
+                            start = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(from) = start {
+            data.push((from, self.words.len() as u64 * 64));
+        }
+
+        // SAFETY: Ranges are built in order and are never contiguous because
+        // a range only ends on a zero bit.
+        Cow::Owned(unsafe { RangeVec::from_raw_unchecked(data) })
+
+    }
+
+    fn gaps(&self, from: u64, to: u64) -> RangeVec<u64> {
+
+        let mut data = Vec::new();
+        // Start of the gap being built, if any.
+        let mut start = None;
+
+        let mut block = from;
+        while block < to {
+
+            let bit = block % 64;
+            let word = self.words.get((block / 64) as usize).map_or(0, |&word| word >> bit);
+            let filled = word & 1 != 0;
+
+            // Zeros are shifted in, so runs of filled blocks end in the word,
+            // runs of missing blocks are limited to the word.
+            let run = if filled { word.trailing_ones() } else { word.trailing_zeros() };
+            let end = (block + run as u64).min(block - bit + 64).min(to);
+
+            match (filled, start) {
+                (false, None) => start = Some(block),
+                (true, Some(from)) => {
+                    data.push((from, block));
+                    start = None;
+                }
+                _ => {}
+            }
+
+            block = end;
+
+        }
+
+        if let Some(from) = start {
+            data.push((from, to));
+        }
+
+        // SAFETY: Gaps are built in order and are never contiguous because
+        // a gap only ends on a filled block.
+        unsafe { RangeVec::from_raw_unchecked(data) }
+
+    }
+
+    #[inline]
+    fn count(&self) -> u64 {
+        self.count
+    }
+
+}
+
+/// The representation of filled blocks used by a partial file, see
+/// [`PartialOptions::block_set`](super::PartialOptions::block_set).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockSetKind {
+    /// A [`RangeVec`], for files filled sequentially.
+    Ranges,
+    /// A [`BlockBitmap`], for files filled randomly.
+    Bitmap,
+}
+
+/// Filled blocks of a partial file, in the representation of its kind.
+#[derive(Debug, Clone)]
+pub(crate) enum Blocks {
+    Ranges(RangeVec<u64>),
+    Bitmap(BlockBitmap),
+}
+
+impl Blocks {
+
+    /// Create blocks of the given kind from their ranges.
+    pub fn new(kind: BlockSetKind, ranges: RangeVec<u64>) -> Self {
+        match kind {
+            BlockSetKind::Ranges => Blocks::Ranges(ranges),
+            BlockSetKind::Bitmap => {
+                let mut bitmap = BlockBitmap::new();
+                for &(from, to) in ranges.get_ranges() {
+                    bitmap.insert(from, to);
+                }
+                Blocks::Bitmap(bitmap)
+            }
+        }
+    }
+
+    /// Return true if all blocks below the given count are in this set, in
+    /// constant time, the set must not contain any block after them.
+    #[inline]
+    pub fn is_full(&self, block_count: u64) -> bool {
+        match self {
+            Blocks::Ranges(ranges) => block_count == 0 || ranges.get_ranges() == [(0, block_count)],
+            Blocks::Bitmap(bitmap) => bitmap.count() == block_count,
+        }
+    }
+
+    #[inline]
+    pub fn kind(&self) -> BlockSetKind {
+        match self {
+            Blocks::Ranges(_) => BlockSetKind::Ranges,
+            Blocks::Bitmap(_) => BlockSetKind::Bitmap,
+        }
+    }
+
+}
+
+impl BlockSet for Blocks {
+
+    #[inline]
+    fn insert(&mut self, from: u64, to: u64) {
+        match self {
+            Blocks::Ranges(ranges) => ranges.insert(from, to),
+            Blocks::Bitmap(bitmap) => bitmap.insert(from, to),
+        }
+    }
+
+    #[inline]
+    fn remove(&mut self, from: u64, to: u64) {
+        match self {
+            Blocks::Ranges(ranges) => BlockSet::remove(ranges, from, to),
+            Blocks::Bitmap(bitmap) => bitmap.remove(from, to),
+        }
+    }
+
+    #[inline]
+    fn contains(&self, block: u64) -> bool {
+        match self {
+            Blocks::Ranges(ranges) => BlockSet::contains(ranges, block),
+            Blocks::Bitmap(bitmap) => bitmap.contains(block),
+        }
+    }
+
+    #[inline]
+    fn ranges(&self) -> Cow<'_, RangeVec<u64>> {
+        match self {
+            Blocks::Ranges(ranges) => ranges.ranges(),
+            Blocks::Bitmap(bitmap) => bitmap.ranges(),
+        }
+    }
+
+    #[inline]
+    fn gaps(&self, from: u64, to: u64) -> RangeVec<u64> {
+        match self {
+            Blocks::Ranges(ranges) => BlockSet::gaps(ranges, from, to),
+            Blocks::Bitmap(bitmap) => bitmap.gaps(from, to),
+        }
+    }
+
+    #[inline]
+    fn count(&self) -> u64 {
+        match self {
+            Blocks::Ranges(ranges) => ranges.count(),
+            Blocks::Bitmap(bitmap) => bitmap.count(),
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn check<B: BlockSet>(mut set: B) {
+
+        set.insert(3, 7);
+        set.insert(60, 130);
+        assert_eq!(set.ranges().get_ranges(), &[(3, 7), (60, 130)]);
+        assert_eq!(set.count(), 74);
+
+        set.insert(7, 10);
+        assert_eq!(set.ranges().get_ranges(), &[(3, 10), (60, 130)]);
+
+        set.remove(64, 128);
+        assert_eq!(set.ranges().get_ranges(), &[(3, 10), (60, 64), (128, 130)]);
+        assert_eq!(set.gaps(0, 200).get_ranges(), &[(0, 3), (10, 60), (64, 128), (130, 200)]);
+        assert_eq!(set.gaps(5, 62).get_ranges(), &[(10, 60)]);
+        assert_eq!(set.gaps(64, 128).get_ranges(), &[(64, 128)]);
+        assert_eq!(set.gaps(3, 10).get_ranges(), &[]);
+        assert!(set.contains(63));
+        assert!(!set.contains(64));
+        assert!(!set.contains(127));
+        assert!(set.contains(128));
+        assert!(!set.contains(1000));
+        assert_eq!(set.count(), 13);
+
+        set.remove(0, 1000);
+        assert_eq!(set.ranges().get_ranges(), &[]);
+        assert_eq!(set.count(), 0);
+
+        set.insert(0, 64);
+        set.insert(64, 128);
+        assert_eq!(set.ranges().get_ranges(), &[(0, 128)]);
+        set.remove(1, 2);
+        assert_eq!(set.ranges().get_ranges(), &[(0, 1), (2, 128)]);
+        set.insert(0, 10);
+        assert_eq!(set.count(), 128);
+
+    }
+
+    #[test]
+    fn range_vec() {
+        check(RangeVec::new());
+    }
+
+    #[test]
+    fn bitmap() {
+        check(BlockBitmap::new());
+    }
+
+    #[test]
+    fn blocks() {
+        check(Blocks::new(BlockSetKind::Ranges, RangeVec::new()));
+        check(Blocks::new(BlockSetKind::Bitmap, RangeVec::new()));
+        let ranges = RangeVec::from_raw(vec![(1, 3), (64, 70)]).unwrap();
+        let blocks = Blocks::new(BlockSetKind::Bitmap, ranges.clone());
+        assert_eq!(blocks.kind(), BlockSetKind::Bitmap);
+        assert_eq!(blocks.ranges().into_owned(), ranges);
+    }
+
+}
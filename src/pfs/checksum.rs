@@ -0,0 +1,47 @@
+//! Block checksums.
+
+
+/// Compute the Fletcher-64 checksum of the given data, as used by the
+/// peerfs protocol. Data is read as little endian 32 bits words, the last
+/// word is padded with zeros if needed.
+pub fn fletcher64(data: &[u8]) -> u64 {
+
+    const MOD: u64 = u32::MAX as u64;
+
+    let mut sum1 = 0u64;
+    let mut sum2 = 0u64;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64;
+        sum1 = (sum1 + word) % MOD;
+        sum2 = (sum2 + sum1) % MOD;
+    }
+
+    let rem = chunks.remainder();
+    if !rem.is_empty() {
+        let mut word = [0; 4];
+        word[..rem.len()].copy_from_slice(rem);
+        sum1 = (sum1 + u32::from_le_bytes(word) as u64) % MOD;
+        sum2 = (sum2 + sum1) % MOD;
+    }
+
+    (sum2 << 32) | sum1
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn fletcher() {
+        assert_eq!(fletcher64(b""), 0);
+        assert_eq!(fletcher64(b"abcde"), 0xC8C6C527646362C6);
+        assert_eq!(fletcher64(b"abcdef"), 0xC8C72B276463C8C6);
+        assert_eq!(fletcher64(b"abcdefgh"), 0x312E2B28CCCAC8C6);
+    }
+
+}
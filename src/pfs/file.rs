@@ -1,33 +1,119 @@
 //! Partial file implementation.
 
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::fs::File;
+use std::io::{self, Cursor, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::ops::{Deref, Range};
+use std::ffi::OsString;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use std::task::Poll;
+use std::fs::{self, File};
 use std::fmt;
 
-use byteorder::{WriteBytesExt, LE, ReadBytesExt};
+use memmap2::{Mmap, MmapOptions};
 
 use crate::range::RangeVec;
 
+use super::block::{BlockSet, BlockSetKind, Blocks};
+use super::footer::Footer;
+use super::journal::{Journal, Record};
+use super::checksum::fletcher64;
+use super::merkle::{merkle_leaf, MerkleHash, MerkleTree};
+use super::pio;
 
-/// Block size for partial files.
-pub const BLOCK_LEN: usize = 4096;
+
+/// Default length of blocks for partial files.
+pub const DEFAULT_BLOCK_LEN: usize = 4096;
+
+/// Former name of [`DEFAULT_BLOCK_LEN`].
+#[deprecated(note = "renamed to DEFAULT_BLOCK_LEN")]
+pub const BLOCK_LEN: usize = DEFAULT_BLOCK_LEN;
+
+/// Extension appended to the data file's name for sidecar metadata files.
+pub const SIDECAR_EXTENSION: &str = "pfs-meta";
+
+/// Extension appended to the data file's name for journal files.
+pub const JOURNAL_EXTENSION: &str = "pfs-journal";
 
 
 /// A partial file, used to store a file blocks by blocks.
+///
+/// All methods taking a shared reference use positional IO and synchronize
+/// the partial state internally, so a partial file can be shared between
+/// threads, for example behind an [`Arc`](std::sync::Arc). The [`Read`],
+/// [`Write`] and [`Seek`] implementations use a cursor owned by this
+/// value and are built on these methods.
 pub struct PartialFile<F: PartialFiller> {
-    /// Underlying memory mapped file (for seek and read/write).
+    /// Underlying file, only accessed with positional IO.
     file: File,
+    /// Path of the underlying file, used to locate the sidecar file.
+    path: PathBuf,
+    /// Length of blocks of this file, only the last block can be shorter.
+    block_len: u64,
+    /// Mutable state of this file, shared between readers and writers.
+    state: Mutex<PartialState>,
+    /// Notified when pending blocks are filled or cancelled.
+    filled: Condvar,
+    /// Position of the cursor used by [`Read`], [`Write`] and [`Seek`].
+    pos: u64,
+    /// The block provider used to try to fill missing blocks if file
+    /// is in partial mode.
+    filler: F,
+}
+
+/// The mutable state of a partial file.
+#[derive(Debug)]
+struct PartialState {
+    /// Where the partial metadata is stored.
+    storage: PartialStorage,
+    /// Representation of filled blocks, also kept in full mode.
+    block_set: BlockSetKind,
     /// Set to `true` when the partial file has been modified and need
     /// to be save its partial metadata footer.
     dirty: bool,
+    /// Journal of changes since the last checkpoint, in partial mode.
+    journal: Option<Journal>,
+    /// Policy used to make checkpoints.
+    flush: FlushPolicy,
+    /// Number of blocks filled or removed since the last checkpoint.
+    unflushed: u64,
+    /// Time of the last checkpoint.
+    last_flush: Instant,
+    /// Result of the completion of the file, given to the filler once the
+    /// state is unlocked.
+    completed: Option<io::Result<()>>,
+    /// Set when all blocks are filled and the file must be verified against
+    /// its Merkle root, once the state is unlocked, before being completed.
+    verify: bool,
+    /// Incremented each time blocks are filled, used to detect changes while
+    /// the file is verified with the state unlocked.
+    generation: u64,
     /// Real size of this file, partial or not.
     size: u64,
     /// Internal mode of this file.
     mode: PartialMode,
-    /// The block provider used to try to fill missing blocks if file
-    /// is in partial mode.
-    filler: F,
+    /// Known Fletcher-64 checksums of blocks, either computed when a block
+    /// is filled or given by peers before.
+    checksums: BTreeMap<u64, u64>,
+    /// Merkle tree of this file, if its root is known, used to verify
+    /// blocks given by untrusted peers.
+    merkle: Option<MerkleTree>,
+    /// Blocks for which the filler returned pending, readers of these blocks
+    /// are waiting for them to be filled or cancelled.
+    pending: BTreeSet<u64>,
+    /// Readahead policy used when reading blocks.
+    readahead: ReadaheadPolicy,
+    /// Last block read, used to detect sequential reads.
+    last_read: Option<u64>,
+    /// Current readahead window of the adaptive policy.
+    readahead_window: u64,
+    /// Memory mapping of the file reused by new mapped ranges, if any.
+    mmap: Option<Arc<Mmap>>,
+    /// Number of mapped ranges alive, existing data can't be modified and
+    /// filled blocks can't become missing while this is not zero.
+    mappings: usize,
 }
 
 /// The internal mode of this file.
@@ -35,337 +121,3050 @@ pub struct PartialFile<F: PartialFiller> {
 enum PartialMode {
     /// The file is partially filled.
     Partial {
-        /// Ranges of filled blocks in this partial file.
-        blocks: RangeVec<u64>,
-        /// True if the block at the cursor has already been fetched.
-        block_state: BlockState,
-        /// Current block's length.
-        block_len: usize
+        /// Filled blocks in this partial file.
+        blocks: Blocks,
     },
     /// The partial file is fully filled.
     Full
 }
 
-/// State for the current block, only used in partial mode.
-#[repr(u8)]
+/// Where the partial metadata of a file is stored, both are handled the
+/// same way by [`PartialFile`], see [`PartialFile::set_storage`] to convert.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum BlockState {
-    /// Unknown state, to check on next data read.
-    Unknown,
-    /// The current block has been fetched but is invalid.
-    Invalid,
-    /// The current block has been fetched but doesn't provide data.
-    Valid
+pub enum PartialStorage {
+    /// Metadata is stored in a footer right after the real data, the file
+    /// looks corrupted to other tools until it's complete.
+    Footer,
+    /// Metadata is stored in a sidecar file next to the data file, with
+    /// [`SIDECAR_EXTENSION`] appended to its name. The data file has its
+    /// real size and missing blocks are holes, so other tools can read
+    /// filled parts while the file is being filled.
+    Sidecar,
 }
 
-impl PartialMode {
+/// Policy used to request blocks to the filler before they are read, this
+/// is useful with fillers returning pending, because following blocks can
+/// be requested while waiting for the block being read.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReadaheadPolicy {
+    /// Only blocks being read are requested.
+    Off,
+    /// When a block is read, the given number of following blocks are
+    /// also requested.
+    Fixed(u64),
+    /// The number of following blocks requested doubles on each sequential
+    /// read, up to the given maximum, and is reset on non-sequential reads.
+    /// A maximum of zero behaves like [`Self::Off`].
+    Adaptive(u64),
+}
 
-    #[inline]
-    fn new_partial(blocks: RangeVec<u64>) -> PartialMode {
-        PartialMode::Partial {
-            blocks,
-            block_state: BlockState::Unknown,
-            block_len: 0
+/// Policy used to write the partial metadata. Changes of filled blocks and
+/// checksums are always appended to a journal file next to the data file,
+/// with [`JOURNAL_EXTENSION`] appended to its name, and are replayed when
+/// opening the file after a crash. The journal is not synchronized with the
+/// data, so replayed blocks are checked against their journaled checksum
+/// and are missing again if their data was lost in a crash of the system.
+/// The metadata is written and synchronized with the data on checkpoints,
+/// blocks filled before the last checkpoint are therefore never lost. A
+/// checkpoint is always made when syncing or dropping the file.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlushPolicy {
+    /// Checkpoints are only made when syncing or dropping the file.
+    Manual,
+    /// A checkpoint is made once the given number of blocks have been
+    /// filled or removed.
+    Blocks(u64),
+    /// A checkpoint is made on the first change after the given duration
+    /// since the last checkpoint.
+    Interval(Duration),
+}
+
+/// Options used to create or open partial files, see [`PartialFile::create`]
+/// for default options.
+#[derive(Debug, Clone)]
+pub struct PartialOptions {
+    storage: PartialStorage,
+    block_len: u32,
+    readahead: ReadaheadPolicy,
+    flush: FlushPolicy,
+    block_set: BlockSetKind,
+}
+
+impl PartialOptions {
+
+    pub fn new() -> Self {
+        Self {
+            storage: PartialStorage::Footer,
+            block_len: DEFAULT_BLOCK_LEN as u32,
+            readahead: ReadaheadPolicy::Off,
+            flush: FlushPolicy::Manual,
+            block_set: BlockSetKind::Ranges,
         }
     }
 
-    #[inline]
-    fn is_partial(&self) -> bool {
-        matches!(self, PartialMode::Partial { .. })
+    /// Set where the partial metadata is stored.
+    pub fn storage(&mut self, storage: PartialStorage) -> &mut Self {
+        self.storage = storage;
+        self
     }
 
-    #[inline]
-    fn is_full(&self) -> bool {
-        matches!(self, PartialMode::Full)
+    /// Set the length of blocks, [`DEFAULT_BLOCK_LEN`] by default. It is
+    /// stored in the partial metadata and can't be changed afterward, full
+    /// files have no metadata so it must be given again to [`Self::open`].
+    pub fn block_len(&mut self, block_len: u32) -> &mut Self {
+        assert_ne!(block_len, 0, "Invalid block length.");
+        self.block_len = block_len;
+        self
     }
 
-}
+    /// Set the readahead policy, off by default, it can be changed later
+    /// with [`PartialFile::set_readahead`].
+    pub fn readahead(&mut self, readahead: ReadaheadPolicy) -> &mut Self {
+        self.readahead = readahead;
+        self
+    }
 
-impl<F: PartialFiller> PartialFile<F> {
+    /// Set the policy used to write the partial metadata, manual by default,
+    /// it can be changed later with [`PartialFile::set_flush_policy`].
+    pub fn flush(&mut self, flush: FlushPolicy) -> &mut Self {
+        self.flush = flush;
+        self
+    }
 
-    pub fn create<P: AsRef<Path>>(path: P, size: u64, filler: F) -> io::Result<Self> {
-        let mut ret = PartialFile {
-            file: File::create(path)?,
-            dirty: true,
-            size,
-            mode: PartialMode::new_partial(RangeVec::new()),
+    /// Set the representation of filled blocks, ranges by default, it can be
+    /// changed later with [`PartialFile::set_block_set`].
+    pub fn block_set(&mut self, block_set: BlockSetKind) -> &mut Self {
+        self.block_set = block_set;
+        self
+    }
+
+    /// Create a new, empty, partial file with these options.
+    pub fn create<P, F>(&self, path: P, size: u64, filler: F) -> io::Result<PartialFile<F>>
+    where
+        P: AsRef<Path>,
+        F: PartialFiller
+    {
+
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        // Missing blocks are left as holes, they are not allocated on
+        // filesystems supporting sparse files, see [`PartialFile::recover_blocks`].
+        file.set_len(size)?;
+
+        let ret = PartialFile {
+            file,
+            path: path.to_path_buf(),
+            block_len: self.block_len as u64,
+            state: Mutex::new(PartialState {
+                dirty: true,
+                ..PartialState::new(
+                    self.storage,
+                    self.block_set,
+                    size,
+                    PartialMode::new_partial(Blocks::new(self.block_set, RangeVec::new())),
+                    Some(Journal::create(PartialFile::<F>::calc_journal_path(path))?),
+                    self.flush,
+                    self.readahead)
+            }),
+            filled: Condvar::new(),
+            pos: 0,
             filler,
         };
-        ret.flush_partial()?;
+
+        // A file of size zero has no block and is already complete.
+        let mut state = ret.lock_state();
+        ret.flush_partial(&mut state)?;
+        ret.check_complete(&mut state);
+        drop(state);
+        ret.notify_completed();
         Ok(ret)
-    }
 
-    pub fn open<P: AsRef<Path>>(path: P, filler: F) -> io::Result<Self> {
+    }
 
+    /// Open an existing file with these options, see [`PartialFile::open`].
+    /// The storage and the block length of partial files are those of their
+    /// metadata, these options are only used for full files, which have no
+    /// metadata.
+    pub fn open<P, F>(&self, path: P, filler: F) -> io::Result<PartialFile<F>>
+    where
+        P: AsRef<Path>,
+        F: PartialFiller
+    {
+
+        let path = path.as_ref();
         let mut file = File::options().read(true).write(true).open(path)?;
         let file_len = file.metadata()?.len();
 
-        // Here we check if this file is in partial mode.
-        file.seek(SeekFrom::End(-8))?;
-        let footer_length = file.read_u64::<LE>()?;
+        // A journal is only left if the process crashed while the file was
+        // open, its records are replayed and a checkpoint is then made.
+        let journal_path = PartialFile::<F>::calc_journal_path(path);
+        let (mut journal, replay) = match Journal::open(&journal_path) {
+            Ok((journal, replay)) => (Some(journal), replay),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (None, Default::default()),
+            Err(err) => return Err(err)
+        };
+
+        let sidecar_path = PartialFile::<F>::calc_sidecar_path(path);
+        let checkpoint = replay.checkpoint.is_some();
+
+        let footer = match replay.checkpoint {
+            Some((storage, buf)) => {
+                // The metadata may have been torn while being written, so the
+                // footer of the checkpoint is used and written again.
+                let footer = Footer::read_sidecar(Cursor::new(&buf), buf.len() as u64)?;
+                match storage {
+                    PartialStorage::Footer => match fs::remove_file(&sidecar_path) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => {}
+                    }
+                    PartialStorage::Sidecar => file.set_len(footer.size)?,
+                }
+                Some((footer, storage))
+            }
+            None => match File::open(&sidecar_path) {
+                Ok(mut sidecar_file) => {
+                    let sidecar_len = sidecar_file.metadata()?.len();
+                    let sidecar = Footer::read_sidecar(&mut sidecar_file, sidecar_len)
+                        .and_then(|footer| footer.check_data_len(file_len).map(|()| footer));
+                    drop(sidecar_file);
+                    match sidecar {
+                        Ok(footer) => Some((footer, PartialStorage::Sidecar)),
+                        // A crash while converting the storage can leave both the
+                        // sidecar file and the footer, which is then used if valid.
+                        Err(err) => match Footer::read(&mut file, file_len) {
+                            Ok(Some(footer)) => {
+                                fs::remove_file(&sidecar_path)?;
+                                Some((footer, PartialStorage::Footer))
+                            }
+                            _ => return Err(err)
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    // Here we check if this file is in partial mode.
+                    Footer::read(&mut file, file_len)?
+                        .map(|footer| (footer, PartialStorage::Footer))
+                }
+                Err(err) => return Err(err)
+            }
+        };
 
-        file.seek(SeekFrom::End(-(footer_length as i64)))?;
-        let size = file.read_u64::<LE>()?;
+        let (block_len, mut state) = match footer {
+            Some((footer, storage)) => (footer.block_len as u64, PartialState {
+                dirty: checkpoint || !replay.records.is_empty(),
+                checksums: footer.checksums.into_owned(),
+                merkle: footer.merkle.map(Cow::into_owned),
+                ..PartialState::new(
+                    storage,
+                    self.block_set,
+                    footer.size,
+                    PartialMode::new_partial(Blocks::new(self.block_set, footer.blocks.into_owned())),
+                    match journal {
+                        Some(journal) => Some(journal),
+                        None => Some(Journal::create(&journal_path)?),
+                    },
+                    self.flush,
+                    self.readahead)
+            }),
+            None => {
+                // The file was completed, the journal is no longer relevant.
+                if journal.take().is_some() {
+                    fs::remove_file(&journal_path)?;
+                }
+                (self.block_len as u64, PartialState::new(
+                    self.storage,
+                    self.block_set,
+                    file_len,
+                    PartialMode::Full,
+                    None,
+                    self.flush,
+                    self.readahead))
+            }
+        };
 
-        let partial = size + footer_length == file_len;
-        let mode = if partial {
+        let block_count = PartialFile::<F>::calc_last_block_index(state.size, block_len);
 
-            // If we guessed that this file is partially filled, parse ranges.
-            let mut blocks = RangeVec::new();
-            let ranges_count = file.read_u64::<LE>()?;
+        if let PartialMode::Partial { ref mut blocks } = state.mode {
 
-            // Test if the remaining header length is strictly equal to the
-            // expected length for ranges.
-            let expected_ranges_size = ranges_count * 16;
-            let actual_ranges_size = footer_length - 24; // -(file_size + header_size + ranges_count)
-            if expected_ranges_size != actual_ranges_size {
-                for _ in 0..ranges_count {
-                    let from = file.read_u64::<LE>()?;
-                    let to = file.read_u64::<LE>()?;
-                    blocks.push(from, to);
+            let mut added = RangeVec::new();
+            for record in replay.records {
+                match record {
+                    Record::Add { from, to } => {
+                        let to = to.min(block_count);
+                        if from < to {
+                            blocks.insert(from, to);
+                            added.push(from, to);
+                        }
+                    }
+                    Record::Remove { from, to } => blocks.remove(from, to),
+                    Record::Checksum { index, checksum } => {
+                        state.checksums.insert(index, checksum);
+                    }
                 }
-                PartialMode::new_partial(blocks)
-            } else {
-                PartialMode::Full
             }
 
-        } else {
-            PartialMode::Full
-        };
+            // The data of replayed blocks may not have been synchronized, so
+            // it's checked against the checksum journaled with each block.
+            let replayed = added.iter_values()
+                .filter(|&index| blocks.contains(index))
+                .collect::<Vec<_>>();
+            for index in replayed {
+                let mut data = vec![0; PartialFile::<F>::calc_block_len(state.size, block_len, index)];
+                let offset = PartialFile::<F>::calc_block_offset(block_len, index);
+                let valid = pio::read_exact_at(&file, &mut data, offset).is_ok()
+                    && state.checksums.get(&index) == Some(&fletcher64(&data));
+                if !valid {
+                    blocks.remove(index, index + 1);
+                }
+            }
 
-        let size = if mode.is_partial() { size } else { file_len };
+        }
 
-        Ok(PartialFile {
+        let ret = PartialFile {
             file,
-            dirty: false,
-            size,
-            mode,
+            path: path.to_path_buf(),
+            block_len,
+            state: Mutex::new(state),
+            filled: Condvar::new(),
+            pos: 0,
             filler
-        })
-
-    }
-
-    fn flush_partial(&mut self) -> io::Result<()> {
-
-        debug_assert!(self.mode.is_partial(), "expected partial mode");
+        };
 
-        if let PartialMode::Partial { ref blocks, .. } = self.mode {
+        {
+            let mut state = ret.lock_state();
+            if state.dirty {
+                ret.flush_partial(&mut state)?;
+            }
+        }
 
-            // Write actual footer.
-            self.file.seek(SeekFrom::Start(self.size))?;
-            self.file.write_u64::<LE>(self.size)?;
+        Ok(ret)
 
-            let ranges = blocks.get_ranges();
-            self.file.write_u64::<LE>(ranges.len() as u64)?;
+    }
 
-            for &(from, to) in ranges {
-                self.file.write_u64::<LE>(from)?;
-                self.file.write_u64::<LE>(to)?;
+    /// Recover a partial file of the given size whose partial metadata is
+    /// missing or corrupted, the file is truncated to this size to remove
+    /// any previous footer, and new metadata is written with these options.
+    /// Filled blocks are then found with [`PartialFile::recover_blocks`].
+    pub fn recover<P, F>(&self, path: P, size: u64, filler: F) -> io::Result<PartialFile<F>>
+    where
+        P: AsRef<Path>,
+        F: PartialFiller
+    {
+
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        file.set_len(size)?;
+
+        // A previous sidecar file would be used instead of the new footer.
+        if self.storage == PartialStorage::Footer {
+            match fs::remove_file(PartialFile::<F>::calc_sidecar_path(path)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
             }
+        }
 
-            // Write footer length.
-            let real_size = self.file.seek(SeekFrom::Current(0))?;
-            let footer_length = real_size - self.size;
-            self.file.write_u64::<LE>(footer_length + 8)?; // + 8 for the footer length itself
+        let ret = PartialFile {
+            file,
+            path: path.to_path_buf(),
+            block_len: self.block_len as u64,
+            state: Mutex::new(PartialState {
+                dirty: true,
+                ..PartialState::new(
+                    self.storage,
+                    self.block_set,
+                    size,
+                    PartialMode::new_partial(Blocks::new(self.block_set, RangeVec::new())),
+                    Some(Journal::create(PartialFile::<F>::calc_journal_path(path))?),
+                    self.flush,
+                    self.readahead)
+            }),
+            filled: Condvar::new(),
+            pos: 0,
+            filler,
+        };
 
-            self.dirty = false;
+        ret.flush_partial(&mut ret.lock_state())?;
+        ret.recover_blocks()?;
+        Ok(ret)
 
-        }
+    }
 
-        Ok(())
+}
 
+impl Default for PartialOptions {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn complete_partial(&mut self) -> io::Result<()> {
-        self.mode = PartialMode::Full;
-        self.file.set_len(self.size)
-    }
+impl PartialMode {
 
     #[inline]
-    pub fn is_partial(&self) -> bool {
-        self.mode.is_partial()
+    fn new_partial(blocks: Blocks) -> PartialMode {
+        PartialMode::Partial { blocks }
     }
 
     #[inline]
-    pub fn is_full(&self) -> bool {
-        self.mode.is_full()
+    fn is_partial(&self) -> bool {
+        matches!(self, PartialMode::Partial { .. })
     }
 
     #[inline]
-    pub fn get_partial_blocks(&self) -> Option<&RangeVec<u64>> {
-        match self.mode {
-            PartialMode::Partial { ref blocks, .. } => Some(blocks),
-            _ => None
+    fn is_full(&self) -> bool {
+        matches!(self, PartialMode::Full)
+    }
+
+}
+
+impl PartialState {
+
+    /// Create a new state that doesn't need to be written, without known
+    /// checksums nor Merkle tree, the journal is only used in partial mode.
+    fn new(
+        storage: PartialStorage,
+        block_set: BlockSetKind,
+        size: u64,
+        mode: PartialMode,
+        journal: Option<Journal>,
+        flush: FlushPolicy,
+        readahead: ReadaheadPolicy,
+    ) -> Self {
+        Self {
+            storage,
+            block_set,
+            dirty: false,
+            journal,
+            flush,
+            unflushed: 0,
+            last_flush: Instant::now(),
+            completed: None,
+            verify: false,
+            generation: 0,
+            size,
+            mode,
+            checksums: BTreeMap::new(),
+            merkle: None,
+            pending: BTreeSet::new(),
+            readahead,
+            last_read: None,
+            readahead_window: 0,
+            mmap: None,
+            mappings: 0,
         }
     }
 
+    /// Return true if the given block is missing, in partial mode.
     #[inline]
-    fn calc_block_len(size: u64, block: u64) -> usize {
-        let block_offset = block * BLOCK_LEN as u64;
-        if block_offset < size {
-            let remaining_size = size - block_offset;
-            remaining_size.min(BLOCK_LEN as u64) as usize
-        } else {
-            0
+    fn is_missing(&self, index: u64) -> bool {
+        match self.mode {
+            PartialMode::Partial { ref blocks } => !blocks.contains(index),
+            PartialMode::Full => false
         }
     }
 
-    #[inline]
-    fn calc_last_block_index(size: u64) -> u64 {
-        (size + BLOCK_LEN as u64 - 1) / BLOCK_LEN as u64
+}
+
+impl<F: PartialFiller> PartialFile<F> {
+
+    /// Create a new, empty, partial file with a metadata footer.
+    pub fn create<P: AsRef<Path>>(path: P, size: u64, filler: F) -> io::Result<Self> {
+        PartialOptions::new().create(path, size, filler)
+    }
+
+    /// Open an existing file, it's opened in partial mode if it has a
+    /// metadata footer or a sidecar metadata file, and full mode otherwise.
+    /// Default options are used, see [`PartialOptions::open`].
+    pub fn open<P: AsRef<Path>>(path: P, filler: F) -> io::Result<Self> {
+        PartialOptions::new().open(path, filler)
     }
 
+    /// Internal function to lock the mutable state of this file.
     #[inline]
-    fn calc_block_offset(block: u64) -> u64 {
-        block * BLOCK_LEN as u64
+    fn lock_state(&self) -> MutexGuard<'_, PartialState> {
+        self.state.lock().expect("partial file state poisoned")
     }
 
-}
+    /// Internal function to encode the partial metadata as a footer.
+    fn encode_partial(&self, state: &PartialState) -> io::Result<Vec<u8>> {
+
+        let PartialMode::Partial { ref blocks } = state.mode else {
+            unreachable!("expected partial mode");
+        };
+
+        let footer = Footer {
+            size: state.size,
+            block_len: self.block_len as u32,
+            blocks: blocks.ranges(),
+            checksums: Cow::Borrowed(&state.checksums),
+            merkle: state.merkle.as_ref().map(Cow::Borrowed),
+        };
+
+        let mut buf = Vec::new();
+        footer.write(&mut buf)?;
+        Ok(buf)
+
+    }
+
+    fn flush_partial(&self, state: &mut PartialState) -> io::Result<()> {
+
+        debug_assert!(state.mode.is_partial(), "expected partial mode");
+
+        if state.mode.is_partial() {
+
+            let buf = self.encode_partial(state)?;
+            let footer_len = buf.len() as u64;
+
+            // The data is synchronized before the metadata referencing it, the
+            // metadata is also written in the journal beforehand because
+            // it can be torn while being written.
+            self.file.sync_data()?;
+            if let Some(journal) = state.journal.as_mut() {
+                journal.checkpoint(state.storage, &buf)?;
+            }
+
+            match state.storage {
+                PartialStorage::Footer => {
+                    pio::write_all_at(&self.file, &buf, state.size)?;
+                    // The footer may be shorter than the previous one, so we truncate
+                    // the remaining bytes of the previous footer.
+                    self.file.set_len(state.size + footer_len)?;
+                    self.file.sync_data()?;
+                }
+                PartialStorage::Sidecar => {
+                    let mut sidecar_file = File::create(Self::calc_sidecar_path(&self.path))?;
+                    sidecar_file.write_all(&buf)?;
+                    sidecar_file.sync_data()?;
+                }
+            }
+
+            if let Some(journal) = state.journal.as_mut() {
+                journal.reset()?;
+            }
+
+            state.dirty = false;
+            state.unflushed = 0;
+            state.last_flush = Instant::now();
 
-impl<F: PartialFiller> Drop for PartialFile<F> {
-    fn drop(&mut self) {
-        if self.dirty && self.mode.is_partial() {
-            let _ = self.flush_partial();
         }
+
+        Ok(())
+
     }
-}
 
-impl<F: PartialFiller> fmt::Debug for PartialFile<F> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("PartialFile")
-            .field("file", &self.file)
-            .field("size", &self.size)
-            .field("mode", &self.mode)
-            .finish()
+    /// Internal function to record changes of the partial metadata, they are
+    /// appended to the journal and a checkpoint is made if the flush policy
+    /// requires it, the given number of blocks have been filled or removed.
+    fn record_changes(&self, state: &mut PartialState, records: &[Record], blocks: u64) -> io::Result<()> {
+
+        state.dirty = true;
+        state.unflushed += blocks;
+
+        if let Some(journal) = state.journal.as_mut() {
+            journal.append(records)?;
+        }
+
+        let flush = match state.flush {
+            FlushPolicy::Manual => false,
+            FlushPolicy::Blocks(count) => state.unflushed >= count,
+            FlushPolicy::Interval(interval) => state.last_flush.elapsed() >= interval,
+        };
+
+        if flush {
+            self.flush_partial(state)?;
+        }
+
+        Ok(())
+
     }
-}
 
-impl<F: PartialFiller> Read for PartialFile<F> {
+    /// Write the partial metadata if it has been modified and flush the
+    /// underlying file, this makes a checkpoint, see [`FlushPolicy`].
+    pub fn sync(&self) -> io::Result<()> {
+        let mut state = self.lock_state();
+        if state.dirty && state.mode.is_partial() {
+            self.flush_partial(&mut state)?;
+        }
+        (&self.file).flush()
+    }
 
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    /// Convert the storage of the partial metadata, if the file is full
+    /// there is no metadata and only the storage to use if the file become
+    /// partial again is changed. If interrupted, the footer is used when
+    /// the file is opened again.
+    pub fn set_storage(&self, storage: PartialStorage) -> io::Result<()> {
 
-        match self.mode {
-            PartialMode::Partial {
-                ref mut blocks ,
-                ref mut block_state,
-                ref mut block_len,
-            } => {
+        let mut state = self.lock_state();
+        if state.storage == storage {
+            return Ok(());
+        }
+
+        let prev_storage = state.storage;
+        state.storage = storage;
 
-                if let BlockState::Unknown = block_state {
+        if state.mode.is_partial() {
+            // The new metadata is fully written before removing the old one.
+            if let Err(err) = self.flush_partial(&mut state) {
+                state.storage = prev_storage;
+                return Err(err);
+            }
+            match prev_storage {
+                PartialStorage::Footer => self.file.set_len(state.size)?,
+                PartialStorage::Sidecar => fs::remove_file(Self::calc_sidecar_path(&self.path))?,
+            }
+        }
 
-                    let pos = self.file.stream_position()?;
-                    let block = pos / BLOCK_LEN as u64;
+        Ok(())
 
-                    *block_len = Self::calc_block_len(self.size, block);
+    }
 
-                    if blocks.contains(block) {
-                        *block_state = BlockState::Valid;
-                    } else {
+    /// Internal function to switch to full mode, the data is synchronized
+    /// before the partial metadata is removed.
+    fn complete_partial(&self, state: &mut PartialState) -> io::Result<()> {
+        self.file.sync_data()?;
+        state.mode = PartialMode::Full;
+        match state.storage {
+            PartialStorage::Footer => self.file.set_len(state.size)?,
+            PartialStorage::Sidecar => fs::remove_file(Self::calc_sidecar_path(&self.path))?,
+        }
+        state.journal = None;
+        fs::remove_file(Self::calc_journal_path(&self.path))
+    }
 
-                        self.file.seek(SeekFrom::Start(block * BLOCK_LEN as u64))?;
+    /// Internal function to complete the file if all its blocks are filled,
+    /// this must be called after blocks are filled. If the Merkle root is
+    /// known, the whole file is verified beforehand by
+    /// [`Self::notify_completed`] once the state is unlocked, which also
+    /// gives the result to the filler.
+    fn check_complete(&self, state: &mut PartialState) {
 
-                        let mut writer = LimitedWriter {
-                            inner: &mut self.file,
-                            len: *block_len
-                        };
+        state.generation += 1;
+        if !self.is_filled(state) {
+            return;
+        }
 
-                        match self.filler.provide(block, *block_len, &mut writer) {
-                            Ok(_) if writer.len == 0 => {
-                                *block_state = BlockState::Valid;
-                                blocks.push(block, block + 1);
-                                self.file.seek(SeekFrom::Start(pos))?;
-                            }
-                            Ok(_) => {
-                                *block_state = BlockState::Invalid;
-                            }
-                            Err(err) => {
-                                *block_state = BlockState::Invalid;
-                                return Err(err);
-                            }
-                        }
+        if state.merkle.is_some() {
+            state.verify = true;
+        } else {
+            state.completed = Some(self.complete_partial(state));
+        }
 
-                    }
+    }
 
-                }
+    /// Internal function to check if the file is partial and all its blocks
+    /// are filled, a file of size zero has no block.
+    fn is_filled(&self, state: &PartialState) -> bool {
+        let PartialMode::Partial { ref blocks } = state.mode else {
+            return false;
+        };
+        let block_count = Self::calc_last_block_index(state.size, self.block_len);
+        blocks.is_full(block_count)
+    }
 
-                if let BlockState::Valid = block_state {
-                    let len = buf.len().min(*block_len);
-                    self.file.read(&mut buf[..len])
-                } else {
-                    Err(io::ErrorKind::InvalidData.into())
-                }
+    /// Internal function to verify the leaves of the whole file against the
+    /// Merkle root, if known. If it doesn't match, blocks that can't be
+    /// verified with known nodes are missing again, their checksums computed
+    /// from the invalid data are forgotten and an error is returned. Blocks
+    /// can't become missing while the file is mapped, false is then returned.
+    fn verify_complete(&self, state: &mut PartialState, leaves: Vec<MerkleHash>) -> io::Result<bool> {
 
+        let (PartialMode::Partial { ref mut blocks }, Some(merkle)) = (&mut state.mode, state.merkle.as_mut()) else {
+            return Ok(true);
+        };
+
+        if MerkleTree::from_leaves(&leaves).root() == merkle.root() {
+            return Ok(true);
+        } else if state.mappings != 0 {
+            return Ok(false);
+        }
+
+        for (index, leaf) in (0..).zip(leaves) {
+            if !merkle.verify(index, leaf, &[]) {
+                blocks.remove(index, index + 1);
+                state.checksums.remove(&index);
             }
-            PartialMode::Full => {
-                // If the file is full, we use the file's internal cursor.
-                self.file.read(buf)
+        }
+
+        // Forgotten checksums can't be journaled, so a checkpoint is made.
+        self.flush_partial(state)?;
+        Err(io::Error::new(io::ErrorKind::InvalidData, "file doesn't match merkle root"))
+
+    }
+
+    /// Internal function to verify the file if needed and give the result
+    /// of its completion to the filler, if any, the state must not be locked.
+    /// The file is read with the state unlocked, it's only completed if no
+    /// block has been filled or removed meanwhile, otherwise the change is
+    /// checked again by its caller. The verification is deferred while the
+    /// file is mapped.
+    fn notify_completed(&self) {
+
+        let mut state = self.lock_state();
+        if state.mappings == 0 && std::mem::take(&mut state.verify) {
+
+            let generation = state.generation;
+            let size = state.size;
+            drop(state);
+
+            let leaves = (0..Self::calc_last_block_index(size, self.block_len))
+                .map(|index| self.read_block_raw(size, index).map(|data| merkle_leaf(&data)))
+                .collect::<io::Result<Vec<_>>>();
+
+            state = self.lock_state();
+            if state.generation == generation && self.is_filled(&state) {
+                match leaves.and_then(|leaves| self.verify_complete(&mut state, leaves)) {
+                    Ok(true) => state.completed = Some(self.complete_partial(&mut state)),
+                    // The file has been mapped meanwhile.
+                    Ok(false) => state.verify = true,
+                    Err(err) => state.completed = Some(Err(err)),
+                }
             }
+
+        }
+
+        let completed = state.completed.take();
+        drop(state);
+        if let Some(res) = completed {
+            self.filler.completed(res);
         }
 
     }
 
-}
+    /// Set the policy used to make checkpoints of the partial metadata.
+    pub fn set_flush_policy(&self, flush: FlushPolicy) {
+        self.lock_state().flush = flush;
+    }
 
-impl<F: PartialFiller> Seek for PartialFile<F> {
+    /// Get the policy used to make checkpoints of the partial metadata.
+    #[inline]
+    pub fn get_flush_policy(&self) -> FlushPolicy {
+        self.lock_state().flush
+    }
 
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    /// Set the size of this file, when shrinking the blocks after the new size
+    /// are dropped and when growing the new blocks are missing, a full file
+    /// becomes partial in this case. If the last block common to both sizes
+    /// grows, it's missing again because its end is missing. The Merkle tree
+    /// is forgotten because it no longer matches, and pending blocks being
+    /// dropped or resized are cancelled. This fails with an error of kind
+    /// [`io::ErrorKind::ResourceBusy`] while the file is mapped.
+    pub fn set_size(&self, size: u64) -> io::Result<()> {
+
+        let mut guard = self.lock_state();
+        let state = &mut *guard;
+
+        let prev_size = state.size;
+        if prev_size == size {
+            return Ok(());
+        } else if state.mappings != 0 {
+            return Err(Self::new_mapped_error());
+        }
 
-        match self.mode {
-            PartialMode::Partial {
-                ref mut block_state,
-                ..
-            } => {
-                let abs_pos = match pos {
-                    SeekFrom::Start(pos) => pos,
-                    SeekFrom::End(pos) => {
-                        (self.size as i64).saturating_add(pos).max(0) as u64
-                    }
-                    SeekFrom::Current(pos) => {
-                        (self.file.stream_position()? as i64).saturating_add(pos).max(0) as u64
+        let prev_count = Self::calc_last_block_index(prev_size, self.block_len);
+        let count = Self::calc_last_block_index(size, self.block_len);
+
+        // The last block common to both sizes has a different length if the
+        // smallest size is within it.
+        let min_size = prev_size.min(size);
+        let resized_block = (!min_size.is_multiple_of(self.block_len)).then_some(min_size / self.block_len);
+        let mut resized_filled = false;
+
+        match state.mode {
+            PartialMode::Partial { ref mut blocks } => {
+                if count < prev_count {
+                    blocks.remove(count, prev_count);
+                }
+                if let Some(index) = resized_block {
+                    // When shrinking, the block is still filled with a prefix of its
+                    // data, only its checksum changes.
+                    if size < prev_size && blocks.contains(index) {
+                        resized_filled = true;
+                    } else {
+                        blocks.remove(index, index + 1);
                     }
-                }.min(self.size);
-                *block_state = BlockState::Unknown;
-                self.file.seek(SeekFrom::Start(abs_pos))
+                }
             }
-            PartialMode::Full => {
-                self.file.seek(pos)
+            PartialMode::Full if size > prev_size => {
+                let mut blocks = Blocks::new(state.block_set, RangeVec::new());
+                if prev_size >= self.block_len {
+                    blocks.insert(0, prev_size / self.block_len);
+                }
+                state.mode = PartialMode::new_partial(blocks);
+                state.journal = Some(Journal::create(Self::calc_journal_path(&self.path))?);
             }
+            PartialMode::Full => {}
         }
 
-    }
+        state.size = size;
+        state.mmap = None;
+        state.merkle = None;
+        state.last_read = None;
+        state.checksums.retain(|&index, _| index < count && Some(index) != resized_block);
+        state.pending.retain(|&index| index < count && Some(index) != resized_block);
 
-}
+        if let (true, Some(index)) = (resized_filled, resized_block) {
+            let checksum = fletcher64(&self.read_block_raw(size, index)?);
+            state.checksums.insert(index, checksum);
+        }
 
+        if state.mode.is_partial() {
+            // The new metadata is written in the journal before resizing the
+            // file, the previous footer is removed so the new tail is a hole.
+            state.dirty = true;
+            let buf = self.encode_partial(state)?;
+            if let Some(journal) = state.journal.as_mut() {
+                journal.checkpoint(state.storage, &buf)?;
+            }
+            self.file.set_len(min_size)?;
+            self.file.set_len(size)?;
+            self.flush_partial(state)?;
+            // All remaining blocks may be filled when shrinking.
+            self.check_complete(state);
+        } else {
+            self.file.set_len(size)?;
+        }
 
-/// A block provider used to complete missing blocks from [`PartialFile`]s.
-pub trait PartialFiller {
-    fn provide<W: Write>(&self, block_index: u64, block_len: usize, dest: W) -> io::Result<()>;
-}
+        self.filled.notify_all();
+        drop(guard);
+        self.notify_completed();
+        Ok(())
 
-impl PartialFiller for () {
-    fn provide<W: Write>(&self, _block_index: u64, block_len: usize, mut dest: W) -> io::Result<()> {
-        static RES: [u8; BLOCK_LEN] = [0; BLOCK_LEN];
-        dest.write_all(&RES[..block_len])
     }
-}
 
+    /// Evict filled blocks in the given range, they are marked as missing and
+    /// holes are punched in the data file to give their space back, this is
+    /// only supported on Linux. A full file becomes partial again. Evicted
+    /// blocks are refilled by the filler when read, and known checksums are
+    /// kept to check them. The evicted blocks are returned, this fails with
+    /// an error of kind [`io::ErrorKind::ResourceBusy`] while the file is
+    /// mapped.
+    pub fn evict_blocks(&self, from: u64, to: u64) -> io::Result<RangeVec<u64>> {
+
+        let mut state = self.lock_state();
+        let state = &mut *state;
+
+        let block_count = Self::calc_last_block_index(state.size, self.block_len);
+        let to = to.min(block_count);
+        if from >= to {
+            return Ok(RangeVec::new());
+        } else if state.mappings != 0 {
+            return Err(Self::new_mapped_error());
+        }
 
-struct LimitedWriter<W: Write> {
-    inner: W,
-    len: usize
-}
+        let mut range = RangeVec::new();
+        range.push(from, to);
 
-impl<W: Write> Write for LimitedWriter<W> {
+        // Blocks are marked as missing in a checkpoint before their data is removed.
+        let evicted = match state.mode {
+            PartialMode::Partial { ref mut blocks } => {
 
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = buf.len().min(self.len);
-        let read = self.inner.write(&buf[..len])?;
-        self.len -= read;
-        Ok(read)
-    }
+                let evicted = range.difference(&blocks.gaps(from, to));
+                if evicted.get_ranges().is_empty() {
+                    return Ok(evicted);
+                }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
+                blocks.remove(from, to);
+                // Filled blocks are never pending, but a stale pending block
+                // would never be requested again. Missing blocks may be pending
+                // with readers waiting for them, so they are kept.
+                state.pending.retain(|&index| !evicted.contains(index));
+                // The journal isn't synchronized, so a checkpoint is made to
+                // never list blocks as filled once their data is removed.
+                state.dirty = true;
+                self.flush_partial(state)?;
+                evicted
+
+            }
+            PartialMode::Full => {
+                let mut blocks = Blocks::new(state.block_set, RangeVec::new());
+                blocks.insert(0, block_count);
+                blocks.remove(from, to);
+                state.mode = PartialMode::new_partial(blocks);
+                state.journal = Some(Journal::create(Self::calc_journal_path(&self.path))?);
+                self.flush_partial(state)?;
+                range
+            }
+        };
 
-}
\ No newline at end of file
+        for &(from_block, to_block) in evicted.get_ranges() {
+            let offset = Self::calc_block_offset(self.block_len, from_block);
+            let end = Self::calc_block_offset(self.block_len, to_block).min(state.size);
+            pio::punch_hole(&self.file, offset, end - offset)?;
+        }
+
+        Ok(evicted)
+
+    }
+
+    /// Set the readahead policy used when reading blocks.
+    pub fn set_readahead(&self, readahead: ReadaheadPolicy) {
+        let mut state = self.lock_state();
+        state.readahead = readahead;
+        state.readahead_window = 0;
+    }
+
+    #[inline]
+    pub fn get_readahead(&self) -> ReadaheadPolicy {
+        self.lock_state().readahead
+    }
+
+    /// Set the representation of filled blocks, they are converted if the
+    /// file is partial, otherwise it's used if the file becomes partial
+    /// again, see [`PartialOptions::block_set`].
+    pub fn set_block_set(&self, block_set: BlockSetKind) {
+        let mut state = self.lock_state();
+        state.block_set = block_set;
+        if let PartialMode::Partial { ref mut blocks } = state.mode {
+            if blocks.kind() != block_set {
+                *blocks = Blocks::new(block_set, blocks.ranges().into_owned());
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get_block_set(&self) -> BlockSetKind {
+        self.lock_state().block_set
+    }
+
+    #[inline]
+    pub fn get_storage(&self) -> PartialStorage {
+        self.lock_state().storage
+    }
+
+    /// Return the real size of this file, without partial metadata.
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.lock_state().size
+    }
+
+    #[inline]
+    pub fn get_block_len(&self) -> u64 {
+        self.block_len
+    }
+
+    /// Return the number of blocks in this file, including the last one
+    /// that can be shorter.
+    #[inline]
+    pub fn get_block_count(&self) -> u64 {
+        Self::calc_last_block_index(self.get_size(), self.block_len)
+    }
+
+    #[inline]
+    pub fn is_partial(&self) -> bool {
+        self.lock_state().mode.is_partial()
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.lock_state().mode.is_full()
+    }
+
+    /// Return a copy of the filled blocks' ranges, in partial mode.
+    #[inline]
+    pub fn get_partial_blocks(&self) -> Option<RangeVec<u64>> {
+        match self.lock_state().mode {
+            PartialMode::Partial { ref blocks } => Some(blocks.ranges().into_owned()),
+            _ => None
+        }
+    }
+
+    /// Read data at the given offset, without using the cursor. In partial
+    /// mode, missing blocks are filled using the filler and data before a
+    /// block that can't be filled is returned, if any.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+
+        let state = self.lock_state();
+        let end = state.size.min(offset.saturating_add(buf.len() as u64));
+        if offset >= end {
+            return Ok(0);
+        } else if state.mode.is_full() {
+            // If the file is full, we can read the data at once.
+            let len = (end - offset) as usize;
+            drop(state);
+            pio::read_exact_at(&self.file, &mut buf[..len], offset)?;
+            return Ok(len);
+        }
+
+        drop(state);
+
+        // All missing blocks are requested at once, so the filler can batch
+        // them, then we wait for each block in order.
+        let from_block = offset / self.block_len;
+        let to_block = Self::calc_last_block_index(end, self.block_len);
+        let mut request_err = self.request_blocks(from_block, to_block).err();
+
+        let mut read = 0;
+
+        for block in from_block..to_block {
+
+            if let Err(err) = self.wait_block(self.lock_state(), block) {
+                let err = request_err.take().unwrap_or(err);
+                if read == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+
+            let pos = offset + read as u64;
+            let len = (Self::calc_block_offset(self.block_len, block + 1).min(end) - pos) as usize;
+            pio::read_exact_at(&self.file, &mut buf[read..read + len], pos)?;
+            read += len;
+
+        }
+
+        Ok(read)
+
+    }
+
+    /// Read a whole block, filling it using the filler if it's missing.
+    pub fn read_block(&self, index: u64) -> io::Result<Vec<u8>> {
+
+        let size = self.get_size();
+        if index >= Self::calc_last_block_index(size, self.block_len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid block"));
+        }
+
+        let request_res = self.request_blocks(index, index + 1);
+        if let Err(err) = self.wait_block(self.lock_state(), index) {
+            return Err(request_res.err().unwrap_or(err));
+        }
+
+        let mut data = vec![0; Self::calc_block_len(size, self.block_len, index)];
+        pio::read_exact_at(&self.file, &mut data, Self::calc_block_offset(self.block_len, index))?;
+        Ok(data)
+
+    }
+
+    /// Map a range of the file in memory, in partial mode all blocks in this
+    /// range must be filled, but they are not filled by this function. While
+    /// the returned range is alive, existing data can't be written and filled
+    /// blocks can't become missing, such operations return an error of kind
+    /// [`io::ErrorKind::ResourceBusy`]. However, the file must not be
+    /// truncated or modified by other processes.
+    pub fn map_range(&self, offset: u64, len: usize) -> io::Result<MappedRange<'_>> {
+
+        let mut state = self.lock_state();
+        let state = &mut *state;
+
+        let end = match offset.checked_add(len as u64) {
+            Some(end) if end <= state.size => end,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid range"))
+        };
+
+        if let PartialMode::Partial { ref blocks } = state.mode {
+            let from_block = offset / self.block_len;
+            let to_block = Self::calc_last_block_index(end, self.block_len);
+            if len != 0 && !blocks.gaps(from_block, to_block).get_ranges().is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "missing block"));
+            }
+        }
+
+        // The mapping is reused unless the range is beyond it, which happens
+        // if the file has grown since.
+        let mmap = match state.mmap {
+            _ if len == 0 => None,
+            Some(ref mmap) if mmap.len() as u64 >= end => Some(Arc::clone(mmap)),
+            _ => {
+                // SAFETY: The mapping doesn't go beyond the real size, and this
+                // part of the file is never truncated or modified while it's
+                // mapped. Modifications by other processes are documented.
+                let mmap = unsafe { MmapOptions::new().len(state.size as usize).map(&self.file)? };
+                Some(Arc::clone(state.mmap.insert(Arc::new(mmap))))
+            }
+        };
+
+        state.mappings += 1;
+
+        Ok(MappedRange {
+            state: &self.state,
+            mmap,
+            range: offset as usize..end as usize
+        })
+
+    }
+
+    /// Map the whole file in memory, it must be full, see [`Self::map_range`].
+    pub fn map_full(&self) -> io::Result<MappedRange<'_>> {
+        let size = {
+            let state = self.lock_state();
+            if state.mode.is_partial() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "partial file"));
+            }
+            state.size
+        };
+        self.map_range(0, size as usize)
+    }
+
+    /// Mark as filled the missing blocks that are entirely allocated on disk,
+    /// this relies on missing blocks being left as holes in sparse files and
+    /// is only supported on Linux. Blocks with a known checksum are only
+    /// recovered if it matches, checksums given afterward with
+    /// [`Self::set_block_checksum`] are also checked against recovered blocks.
+    /// Allocation is the only evidence for other blocks, so a block only
+    /// partially written but entirely allocated, for example because it's
+    /// no larger than the filesystem's blocks, is also recovered. The
+    /// recovered blocks are returned and the metadata is written.
+    pub fn recover_blocks(&self) -> io::Result<RangeVec<u64>> {
+
+        let mut guard = self.lock_state();
+        let state = &mut *guard;
+        let size = state.size;
+
+        let PartialMode::Partial { ref mut blocks } = state.mode else {
+            return Ok(RangeVec::new());
+        };
+
+        let mut recovered = RangeVec::new();
+
+        for extent in pio::data_extents(&self.file, 0, size)? {
+
+            // Only blocks entirely in the extent are recovered, the last block
+            // can be shorter.
+            let from_block = extent.start.div_ceil(self.block_len);
+            let to_block = if extent.end == size {
+                Self::calc_last_block_index(size, self.block_len)
+            } else {
+                extent.end / self.block_len
+            };
+
+            for index in from_block..to_block {
+
+                if blocks.contains(index) || state.pending.contains(&index) {
+                    continue;
+                }
+
+                let checksum = fletcher64(&self.read_block_raw(size, index)?);
+                if Self::check_checksum(&state.checksums, index, checksum) {
+                    blocks.insert(index, index + 1);
+                    recovered.push(index, index + 1);
+                    state.checksums.insert(index, checksum);
+                }
+
+            }
+
+        }
+
+        if !recovered.get_ranges().is_empty() {
+            self.flush_partial(state)?;
+            self.filled.notify_all();
+        }
+
+        // A file of size zero is complete without any block recovered.
+        self.check_complete(state);
+
+        drop(guard);
+        self.notify_completed();
+        Ok(recovered)
+
+    }
+
+    /// Write data at the given offset, without using the cursor. See the
+    /// [`Write`] implementation for details about partial mode.
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let res = self.write_at_locked(&mut self.lock_state(), offset, buf);
+        self.notify_completed();
+        res
+    }
+
+    /// Internal function to write data at the given offset with the state
+    /// locked, see [`Self::write_at`].
+    fn write_at_locked(&self, state: &mut PartialState, offset: u64, buf: &[u8]) -> io::Result<usize> {
+
+        match state.mode {
+            PartialMode::Partial { ref mut blocks } => {
+
+                // Writes are limited to the real size of the file, this avoids
+                // overwriting the footer.
+                let len = state.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+                if len == 0 {
+                    return Ok(0);
+                }
+
+                // Filled blocks may be mapped, even if partially written.
+                let end = offset + len as u64;
+                if state.mappings != 0 {
+                    let from_block = offset / self.block_len;
+                    let to_block = Self::calc_last_block_index(end, self.block_len);
+                    if blocks.gaps(from_block, to_block).covered_len() != to_block - from_block {
+                        return Err(Self::new_mapped_error());
+                    }
+                }
+
+                // Only blocks entirely covered by this write are marked as
+                // filled, the last block is covered if we wrote up to the end.
+                let from_block = Self::calc_last_block_index(offset, self.block_len);
+                let to_block = if end == state.size {
+                    Self::calc_last_block_index(end, self.block_len)
+                } else {
+                    end / self.block_len
+                };
+
+                // Covered blocks are checked against their known checksums
+                // before writing anything.
+                let mut checksums = Vec::new();
+                for block in from_block..to_block {
+                    let block_start = (Self::calc_block_offset(self.block_len, block) - offset) as usize;
+                    let block_len = Self::calc_block_len(state.size, self.block_len, block);
+                    let checksum = fletcher64(&buf[block_start..block_start + block_len]);
+                    if !Self::check_checksum(&state.checksums, block, checksum) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "block checksum mismatch"));
+                    }
+                    checksums.push((block, checksum));
+                }
+
+                // Filled blocks only partially covered by this write no longer
+                // match their checksum, so they are missing again.
+                let first_block = offset / self.block_len;
+                let last_block = Self::calc_last_block_index(end, self.block_len);
+                let mut records = Vec::new();
+                for index in (first_block..from_block).chain(to_block.max(first_block)..last_block) {
+                    if blocks.contains(index) {
+                        blocks.remove(index, index + 1);
+                        records.push(Record::Remove { from: index, to: index + 1 });
+                    }
+                }
+
+                if !records.is_empty() {
+                    let removed = records.len() as u64;
+                    self.record_changes(state, &records, removed)?;
+                }
+
+                let PartialMode::Partial { ref mut blocks } = state.mode else {
+                    unreachable!("expected partial mode");
+                };
+
+                pio::write_all_at(&self.file, &buf[..len], offset)?;
+
+                if to_block > from_block {
+                    blocks.insert(from_block, to_block);
+                    state.pending.retain(|index| !(from_block..to_block).contains(index));
+                    let mut records = checksums.iter()
+                        .map(|&(index, checksum)| Record::Checksum { index, checksum })
+                        .collect::<Vec<_>>();
+                    records.push(Record::Add { from: from_block, to: to_block });
+                    state.checksums.extend(checksums);
+                    self.filled.notify_all();
+                    self.record_changes(state, &records, to_block - from_block)?;
+                    self.check_complete(state);
+                }
+
+                Ok(len)
+
+            }
+            PartialMode::Full => {
+                if state.mappings != 0 && offset < state.size && !buf.is_empty() {
+                    return Err(Self::new_mapped_error());
+                }
+                pio::write_all_at(&self.file, buf, offset)?;
+                state.size = state.size.max(offset + buf.len() as u64);
+                Ok(buf.len())
+            }
+        }
+
+    }
+
+    /// Set the expected checksum of a block, usually given by a peer. If the
+    /// block is already filled but doesn't match this checksum, it's marked
+    /// as missing again, this fails while the file is mapped. Missing blocks
+    /// are checked against this checksum when filled.
+    pub fn set_block_checksum(&self, index: u64, checksum: u64) -> io::Result<()> {
+
+        let mut state = self.lock_state();
+        let state = &mut *state;
+
+        if let PartialMode::Partial { ref mut blocks } = state.mode {
+
+            let mut records = vec![Record::Checksum { index, checksum }];
+            if blocks.contains(index) {
+                let valid = match state.checksums.get(&index) {
+                    Some(&known) => known == checksum,
+                    None => fletcher64(&self.read_block_raw(state.size, index)?) == checksum
+                };
+                if !valid {
+                    if state.mappings != 0 {
+                        return Err(Self::new_mapped_error());
+                    }
+                    blocks.remove(index, index + 1);
+                    state.pending.remove(&index);
+                    records.push(Record::Remove { from: index, to: index + 1 });
+                }
+            }
+
+            state.checksums.insert(index, checksum);
+            let removed = records.len() as u64 - 1;
+            return self.record_changes(state, &records, removed);
+
+        }
+
+        state.checksums.insert(index, checksum);
+        Ok(())
+
+    }
+
+    /// Get the known checksum of a block.
+    #[inline]
+    pub fn get_block_checksum(&self, index: u64) -> Option<u64> {
+        self.lock_state().checksums.get(&index).copied()
+    }
+
+    /// Verify that the data of a block matches its known checksum, this
+    /// reads the block's data from the underlying file. An error of kind
+    /// [`io::ErrorKind::NotFound`] is returned if the block is missing or if
+    /// its checksum is unknown.
+    pub fn verify_block(&self, index: u64) -> io::Result<bool> {
+
+        let state = self.lock_state();
+
+        if state.is_missing(index) || index >= Self::calc_last_block_index(state.size, self.block_len) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "missing block"));
+        }
+
+        let Some(&checksum) = state.checksums.get(&index) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "unknown block checksum"));
+        };
+
+        Ok(fletcher64(&self.read_block_raw(state.size, index)?) == checksum)
+
+    }
+
+    /// Set the Merkle root of this file, blocks given with a proof through
+    /// [`Self::write_block_verified`] or by the filler are then verified
+    /// against it. Previously verified nodes are forgotten if the root
+    /// changes.
+    pub fn set_merkle_root(&self, root: MerkleHash) {
+        let mut state = self.lock_state();
+        if state.merkle.as_ref().map(MerkleTree::root) != Some(root) {
+            let leaf_count = Self::calc_last_block_index(state.size, self.block_len);
+            state.merkle = Some(MerkleTree::new(root, leaf_count));
+            state.dirty |= state.mode.is_partial();
+        }
+    }
+
+    /// Return a copy of the Merkle tree of this file, if its root is known.
+    #[inline]
+    pub fn get_merkle_tree(&self) -> Option<MerkleTree> {
+        self.lock_state().merkle.clone()
+    }
+
+    /// Write a whole block given by an untrusted peer, the block is verified
+    /// against the Merkle root using the given proof before being written.
+    /// See [`MerkleTree::verify`] for the proof format.
+    pub fn write_block_verified(&self, index: u64, data: &[u8], proof: &[MerkleHash]) -> io::Result<()> {
+
+        {
+            let mut state = self.lock_state();
+
+            let size = state.size;
+            if index >= Self::calc_last_block_index(size, self.block_len) || data.len() != Self::calc_block_len(size, self.block_len, index) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid block"));
+            }
+
+            let Some(merkle) = state.merkle.as_mut() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown merkle root"));
+            };
+
+            if !merkle.verify(index, merkle_leaf(data), proof) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid merkle proof"));
+            }
+
+            state.dirty |= state.mode.is_partial();
+        }
+
+        self.write_at(Self::calc_block_offset(self.block_len, index), data)?;
+        Ok(())
+
+    }
+
+    /// Write a whole block, usually given later by a filler that returned
+    /// pending for it. The block is checked against its known checksum and,
+    /// if the Merkle root is known, verified with the given proof. Readers
+    /// waiting for this block are resumed, it's ignored if already filled.
+    pub fn write_block(&self, index: u64, data: &[u8], proof: &[MerkleHash]) -> io::Result<()> {
+
+        let mut state = self.lock_state();
+
+        let size = state.size;
+        if index >= Self::calc_last_block_index(size, self.block_len) || data.len() != Self::calc_block_len(size, self.block_len, index) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid block"));
+        }
+
+        let res = self.insert_block(&mut state, index, data, proof);
+        drop(state);
+        self.notify_completed();
+        res
+
+    }
+
+    /// Cancel a block for which the filler returned pending, because it
+    /// can't be provided. Readers waiting for this block are resumed with
+    /// an error of kind [`io::ErrorKind::NotFound`].
+    pub fn cancel_block(&self, index: u64) {
+        if self.lock_state().pending.remove(&index) {
+            self.filled.notify_all();
+        }
+    }
+
+    /// Internal function to request missing blocks of the given range,
+    /// extended depending on the readahead policy, to the filler. Blocks are
+    /// pending while the filler is called, so concurrent readers wait for
+    /// them instead of requesting them again, and they stay pending if the
+    /// filler returns pending. Each run of missing blocks is requested at
+    /// once and the state is not locked while the filler is providing data.
+    fn request_blocks(&self, from: u64, to: u64) -> io::Result<()> {
+
+        // A verification deferred while the file was mapped is made first, so
+        // invalid blocks are requested again.
+        self.notify_completed();
+
+        let (runs, size, verify_proof) = {
+
+            let mut state = self.lock_state();
+            let state = &mut *state;
+
+            let to = self.calc_readahead_end(state, from, to);
+
+            let PartialMode::Partial { ref blocks } = state.mode else {
+                return Ok(());
+            };
+
+            // Filled blocks are skipped, and runs are split on blocks that
+            // are already pending.
+            let mut runs = Vec::<(u64, u64)>::new();
+            for &(gap_from, gap_to) in blocks.gaps(from, to).get_ranges() {
+                for block in gap_from..gap_to {
+                    if !state.pending.insert(block) {
+                        continue;
+                    }
+                    match runs.last_mut() {
+                        Some((_, run_to)) if *run_to == block => *run_to += 1,
+                        _ => runs.push((block, block + 1))
+                    }
+                }
+            }
+
+            (runs, state.size, state.merkle.is_some())
+
+        };
+
+        let mut res = Ok(());
+        for (from, to) in runs {
+            if let (Err(err), Ok(_)) = (self.request_run(from, to, size, verify_proof), &res) {
+                res = Err(err);
+            }
+        }
+
+        self.notify_completed();
+        res
+
+    }
+
+    /// Internal function to request a run of pending blocks to the filler,
+    /// blocks that are not given are no longer pending and the first error
+    /// is returned.
+    fn request_run(&self, from: u64, to: u64, size: u64, verify_proof: bool) -> io::Result<()> {
+
+        let offset = Self::calc_block_offset(self.block_len, from);
+        let len = Self::calc_block_offset(self.block_len, to).min(size) - offset;
+
+        let mut data = Vec::with_capacity(len as usize);
+        let writer = LimitedWriter {
+            inner: &mut data,
+            len: len as usize
+        };
+
+        let pending = match self.filler.provide_range(from, to, self.block_len as usize, len, writer) {
+            Ok(pending) => pending,
+            Err(err) => {
+                for block in from..to {
+                    self.cancel_block(block);
+                }
+                return Err(err);
+            }
+        };
+
+        let mut res = Ok(());
+
+        for block in from..to {
+
+            if pending.contains(block) {
+                continue;
+            }
+
+            let start = (Self::calc_block_offset(self.block_len, block) - offset) as usize;
+            let block_len = Self::calc_block_len(size, self.block_len, block);
+
+            let block_res = match data.get(start..start + block_len) {
+                Some(block_data) => {
+                    let proof = if verify_proof {
+                        self.filler.provide_proof(block)
+                    } else {
+                        Ok(Vec::new())
+                    };
+                    proof.and_then(|proof| self.insert_block(&mut self.lock_state(), block, block_data, &proof))
+                }
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete block"))
+            };
+
+            if let Err(err) = block_res {
+                self.cancel_block(block);
+                if res.is_ok() {
+                    res = Err(err);
+                }
+            }
+
+        }
+
+        res
+
+    }
+
+    /// Internal function to compute the end of the blocks to request when
+    /// reading the given range, depending on the readahead policy.
+    fn calc_readahead_end(&self, state: &mut PartialState, from: u64, to: u64) -> u64 {
+
+        let window = match state.readahead {
+            ReadaheadPolicy::Off => 0,
+            ReadaheadPolicy::Fixed(window) => window,
+            ReadaheadPolicy::Adaptive(max_window) => {
+                match state.last_read {
+                    Some(last) if last == from => {}
+                    Some(last) if last + 1 == from => {
+                        state.readahead_window = (state.readahead_window * 2).max(1).min(max_window);
+                    }
+                    _ => state.readahead_window = 0
+                }
+                state.readahead_window
+            }
+        };
+
+        let last = to - 1;
+        state.last_read = Some(last);
+
+        let block_count = Self::calc_last_block_index(state.size, self.block_len);
+        last.saturating_add(window).saturating_add(1).min(block_count)
+
+    }
+
+    /// Internal function to wait for a pending block to be filled.
+    fn wait_block(&self, state: MutexGuard<'_, PartialState>, index: u64) -> io::Result<()> {
+
+        let state = self.filled.wait_while(state, |state| {
+            state.is_missing(index) && state.pending.contains(&index)
+        }).expect("partial file state poisoned");
+
+        if state.is_missing(index) {
+            Err(io::Error::new(io::ErrorKind::NotFound, "cancelled block"))
+        } else {
+            Ok(())
+        }
+
+    }
+
+    /// Internal function to insert a whole block if it's missing, the block
+    /// is rejected if its checksum or its proof doesn't match.
+    fn insert_block(&self, state: &mut PartialState, index: u64, data: &[u8], proof: &[MerkleHash]) -> io::Result<()> {
+
+        let PartialMode::Partial { ref mut blocks } = state.mode else {
+            state.pending.remove(&index);
+            return Ok(());
+        };
+
+        // The block may have been filled concurrently.
+        if blocks.contains(index) {
+            state.pending.remove(&index);
+            return Ok(());
+        }
+
+        let checksum = fletcher64(data);
+        let mut valid = Self::check_checksum(&state.checksums, index, checksum);
+        if let (true, Some(merkle)) = (valid, state.merkle.as_mut()) {
+            valid = merkle.verify(index, merkle_leaf(data), proof);
+        }
+
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid block"));
+        }
+
+        pio::write_all_at(&self.file, data, Self::calc_block_offset(self.block_len, index))?;
+        blocks.insert(index, index + 1);
+        state.checksums.insert(index, checksum);
+        state.pending.remove(&index);
+        self.filled.notify_all();
+        self.record_changes(state, &[
+            Record::Checksum { index, checksum },
+            Record::Add { from: index, to: index + 1 },
+        ], 1)?;
+
+        self.check_complete(state);
+        Ok(())
+
+    }
+
+    /// Internal function to read a block's data from the underlying file,
+    /// without checking if it's filled.
+    fn read_block_raw(&self, size: u64, index: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; Self::calc_block_len(size, self.block_len, index)];
+        pio::read_exact_at(&self.file, &mut data, Self::calc_block_offset(self.block_len, index))?;
+        Ok(data)
+    }
+
+    /// Internal function to check a block's checksum against its known
+    /// checksum, if any.
+    #[inline]
+    fn check_checksum(checksums: &BTreeMap<u64, u64>, index: u64, checksum: u64) -> bool {
+        match checksums.get(&index) {
+            Some(&known) => known == checksum,
+            None => true
+        }
+    }
+
+    #[inline]
+    fn new_mapped_error() -> io::Error {
+        io::Error::new(io::ErrorKind::ResourceBusy, "partial file is mapped")
+    }
+
+    fn calc_sidecar_path(path: &Path) -> PathBuf {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(SIDECAR_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    fn calc_journal_path(path: &Path) -> PathBuf {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(JOURNAL_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    #[inline]
+    fn calc_block_len(size: u64, block_len: u64, block: u64) -> usize {
+        let block_offset = block * block_len;
+        if block_offset < size {
+            let remaining_size = size - block_offset;
+            remaining_size.min(block_len) as usize
+        } else {
+            0
+        }
+    }
+
+    #[inline]
+    fn calc_last_block_index(size: u64, block_len: u64) -> u64 {
+        size.div_ceil(block_len)
+    }
+
+    #[inline]
+    fn calc_block_offset(block_len: u64, block: u64) -> u64 {
+        block * block_len
+    }
+
+}
+
+impl<F: PartialFiller> Drop for PartialFile<F> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            if state.dirty && state.mode.is_partial() && self.flush_partial(&mut state).is_err() {
+                return;
+            }
+            // The journal is only kept if the metadata couldn't be written.
+            if state.journal.take().is_some() {
+                let _ = fs::remove_file(Self::calc_journal_path(&self.path));
+            }
+        }
+    }
+}
+
+impl<F: PartialFiller> fmt::Debug for PartialFile<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialFile")
+            .field("file", &self.file)
+            .field("block_len", &self.block_len)
+            .field("state", &self.state)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+/// A range of a partial file mapped in memory, see [`PartialFile::map_range`].
+pub struct MappedRange<'a> {
+    /// State of the mapped file, used to release the mapping.
+    state: &'a Mutex<PartialState>,
+    /// The underlying mapping, none for empty ranges.
+    mmap: Option<Arc<Mmap>>,
+    /// Range of the mapping.
+    range: Range<usize>,
+}
+
+impl Deref for MappedRange<'_> {
+
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        match self.mmap {
+            Some(ref mmap) => &mmap[self.range.clone()],
+            None => &[]
+        }
+    }
+
+}
+
+impl AsRef<[u8]> for MappedRange<'_> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for MappedRange<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.mappings -= 1;
+        }
+    }
+}
+
+impl fmt::Debug for MappedRange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedRange")
+            .field("range", &self.range)
+            .finish()
+    }
+}
+
+/// In partial mode, reads follow the cursor block by block and missing
+/// blocks are filled using the filler as they are reached. If filling a
+/// block fails after some data has been read, this data is returned and
+/// the error will be returned by the next read.
+impl<F: PartialFiller> Read for PartialFile<F> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read_at(self.pos, buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+
+        let mut read = 0;
+
+        for buf in bufs {
+            let len = match self.read(buf) {
+                Ok(len) => len,
+                Err(_) if read != 0 => break,
+                Err(err) => return Err(err)
+            };
+            read += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        Ok(read)
+
+    }
+
+}
+
+/// In partial mode, writes can't go beyond the real size of the file and
+/// blocks are marked as filled only if a single write covers them entirely,
+/// the data of partially covered blocks is written but these blocks are
+/// considered missing, even if they were filled. In full mode, writes are
+/// not limited.
+impl<F: PartialFiller> Write for PartialFile<F> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.write_at(self.pos, buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+
+}
+
+/// In partial mode, the cursor can't go beyond the real size of the file.
+impl<F: PartialFiller> Seek for PartialFile<F> {
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+
+        let state = self.lock_state();
+
+        let abs_pos = if state.mode.is_partial() {
+            match pos {
+                SeekFrom::Start(pos) => pos,
+                SeekFrom::End(pos) => {
+                    (state.size as i64).saturating_add(pos).max(0) as u64
+                }
+                SeekFrom::Current(pos) => {
+                    (self.pos as i64).saturating_add(pos).max(0) as u64
+                }
+            }.min(state.size)
+        } else {
+            match pos {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::End(pos) => state.size.checked_add_signed(pos),
+                SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+            }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?
+        };
+
+        drop(state);
+        self.pos = abs_pos;
+        Ok(abs_pos)
+
+    }
+
+}
+
+
+/// A block provider used to complete missing blocks from [`PartialFile`]s.
+pub trait PartialFiller {
+
+    /// Provide the data of a block by writing it to the given destination.
+    /// If the data is not immediately available, for example if it must be
+    /// requested to a peer, pending can be returned and the block must then
+    /// be given later with [`PartialFile::write_block`], or abandoned with
+    /// [`PartialFile::cancel_block`]. Readers of the block wait until then
+    /// and data written before returning pending is ignored.
+    fn provide<W: Write>(&self, block_index: u64, block_len: usize, dest: W) -> io::Result<Poll<()>>;
+
+    /// Provide the Merkle proof of a block, only called if the Merkle root
+    /// of the partial file is known. No proof is given by default, so only
+    /// blocks that can be verified with known nodes are accepted.
+    fn provide_proof(&self, _block_index: u64) -> io::Result<Vec<MerkleHash>> {
+        Ok(Vec::new())
+    }
+
+    /// Called once all blocks are filled and the file has been completed,
+    /// its partial metadata is then removed and it's in full mode. If the
+    /// Merkle root is known, the whole file is verified beforehand and an
+    /// error of kind [`io::ErrorKind::InvalidData`] is given if it doesn't
+    /// match, the file stays partial and blocks that can't be verified are
+    /// missing again. The verification is deferred while the file is mapped,
+    /// until the next read or write. Nothing is done by default.
+    fn completed(&self, _result: io::Result<()>) {}
+
+    /// Provide the data of a range of blocks, written contiguously to the
+    /// given destination. The block length is the one of the partial file
+    /// and the total length of the range is given, only the last block of
+    /// the file can be shorter. Blocks that are pending are returned and
+    /// must be given later like for [`Self::provide`], the data written for
+    /// them is ignored but must keep following blocks aligned. Blocks that
+    /// are not entirely written are considered unavailable.
+    ///
+    /// The default implementation calls [`Self::provide`] for each block and
+    /// stops on the first error, which is only returned for the first block.
+    fn provide_range<W: Write>(&self, from_block: u64, to_block: u64, block_len: usize, len: u64, mut dest: W) -> io::Result<RangeVec<u64>> {
+
+        let mut pending = RangeVec::new();
+        let mut remaining = len;
+
+        for block in from_block..to_block {
+
+            let len = remaining.min(block_len as u64) as usize;
+            let mut data = Vec::with_capacity(len);
+            let writer = LimitedWriter {
+                inner: &mut data,
+                len
+            };
+
+            match self.provide(block, len, writer) {
+                Ok(Poll::Ready(())) if data.len() == len => dest.write_all(&data)?,
+                Ok(Poll::Ready(())) => {
+                    dest.write_all(&data)?;
+                    break;
+                }
+                Ok(Poll::Pending) => {
+                    io::copy(&mut io::repeat(0).take(len as u64), &mut dest)?;
+                    pending.push(block, block + 1);
+                }
+                Err(err) if block == from_block => return Err(err),
+                Err(_) => break
+            }
+
+            remaining -= len as u64;
+
+        }
+
+        Ok(pending)
+
+    }
+
+}
+
+impl PartialFiller for () {
+    fn provide<W: Write>(&self, _block_index: u64, block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+        static RES: [u8; DEFAULT_BLOCK_LEN] = [0; DEFAULT_BLOCK_LEN];
+        let mut remaining = block_len;
+        while remaining != 0 {
+            let len = remaining.min(RES.len());
+            dest.write_all(&RES[..len])?;
+            remaining -= len;
+        }
+        Ok(Poll::Ready(()))
+    }
+}
+
+
+struct LimitedWriter<W: Write> {
+    inner: W,
+    len: usize
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.len);
+        let read = self.inner.write(&buf[..len])?;
+        self.len -= read;
+        Ok(read)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::PathBuf;
+    use std::mem;
+
+    use crate::pfs::FooterError;
+
+    use super::*;
+
+    const BLOCK_LEN: usize = DEFAULT_BLOCK_LEN;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("peerfs-{}-{name}", std::process::id()))
+    }
+
+    /// Provide bytes derived from their offset, failing for a given block.
+    struct OffsetFiller {
+        block_len: u64,
+        fail: Option<u64>,
+    }
+
+    impl PartialFiller for OffsetFiller {
+        fn provide<W: Write>(&self, block_index: u64, block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+            if self.fail == Some(block_index) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            let offset = block_index * self.block_len;
+            let data = (offset..offset + block_len as u64).map(|i| i as u8).collect::<Vec<_>>();
+            dest.write_all(&data).map(Poll::Ready)
+        }
+    }
+
+    #[test]
+    fn write() {
+
+        let path = temp_path("write");
+        let size = BLOCK_LEN as u64 * 4 + 100;
+
+        {
+            let mut pf = PartialFile::create(&path, size, ()).unwrap();
+
+            // Partially covered blocks are not marked.
+            pf.write_at(10, &[1; BLOCK_LEN]).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[]);
+
+            pf.write_at(BLOCK_LEN as u64, &[2; BLOCK_LEN * 2]).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3)]);
+
+            // Writes are limited to the file's size and the last block is
+            // marked when written up to the end.
+            pf.seek(SeekFrom::Start(BLOCK_LEN as u64 * 4)).unwrap();
+            assert_eq!(pf.write(&[3; BLOCK_LEN]).unwrap(), 100);
+            assert_eq!(pf.write(&[3; BLOCK_LEN]).unwrap(), 0);
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3), (4, 5)]);
+        }
+
+        let mut pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3), (4, 5)]);
+
+        let mut buf = [0; 100];
+        pf.seek(SeekFrom::Start(BLOCK_LEN as u64 * 2)).unwrap();
+        pf.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2; 100]);
+        pf.seek(SeekFrom::Start(BLOCK_LEN as u64 * 4)).unwrap();
+        pf.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3; 100]);
+
+        // Filled blocks partially covered by a write are missing again.
+        pf.write_at(BLOCK_LEN as u64 + 10, &[9; BLOCK_LEN]).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(4, 5)]);
+        assert_eq!(pf.verify_block(1).unwrap_err().kind(), io::ErrorKind::NotFound);
+        drop(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(4, 5)]);
+
+        drop(pf);
+        std::fs::remove_file(&path).unwrap();
+
+    }
+
+
+    #[test]
+    fn footer() {
+
+        let path = temp_path("footer");
+        let size = BLOCK_LEN as u64 * 2 + 10;
+
+        fn open_err(path: &Path) -> Option<FooterError> {
+            FooterError::from_io(&PartialFile::open(path, ()).unwrap_err())
+        }
+
+        // Ordinary files, even smaller than the footer.
+        for len in [0, 3, 100, 10000] {
+            std::fs::write(&path, vec![7; len]).unwrap();
+            let pf = PartialFile::open(&path, ()).unwrap();
+            assert!(pf.is_full());
+            assert_eq!(pf.get_size(), len as u64);
+        }
+
+        {
+            let pf = PartialFile::create(&path, size, ()).unwrap();
+            pf.write_at(0, &[1; BLOCK_LEN]).unwrap();
+        }
+
+        let valid = std::fs::read(&path).unwrap();
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert!(pf.is_partial());
+        assert_eq!(pf.get_size(), size);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 1)]);
+        drop(pf);
+
+        // Corrupted body.
+        let mut data = valid.clone();
+        data[size as usize + 2] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(open_err(&path), Some(FooterError::Corrupt));
+
+        // Unknown version.
+        let mut data = valid.clone();
+        let version_idx = data.len() - 20;
+        data[version_idx] = 2;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(open_err(&path), Some(FooterError::UnknownVersion(2)));
+
+        // Missing data before the footer.
+        let mut data = valid.clone();
+        data.drain(..BLOCK_LEN);
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(open_err(&path), Some(FooterError::Truncated));
+
+        // Footer longer than the file.
+        let data = valid[valid.len() - 24..].to_vec();
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(open_err(&path), Some(FooterError::Truncated));
+
+        // Extra data before the footer.
+        let mut data = valid.clone();
+        data.splice(0..0, [0; 3]);
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(open_err(&path), Some(FooterError::Corrupt));
+
+        std::fs::write(&path, &valid).unwrap();
+        assert!(PartialFile::open(&path, ()).unwrap().is_partial());
+
+        std::fs::remove_file(&path).unwrap();
+
+    }
+
+
+    #[test]
+    fn sidecar() {
+
+        let path = temp_path("sidecar");
+        let sidecar_path = PartialFile::<()>::calc_sidecar_path(&path);
+        let size = BLOCK_LEN as u64 * 3 + 10;
+
+        {
+            let pf = PartialOptions::new()
+                .storage(PartialStorage::Sidecar)
+                .create(&path, size, ())
+                .unwrap();
+            pf.write_at(BLOCK_LEN as u64, &[1; BLOCK_LEN]).unwrap();
+        }
+
+        // The data file has its real size.
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert!(sidecar_path.exists());
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Sidecar);
+        assert_eq!(pf.get_size(), size);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+
+        pf.set_storage(PartialStorage::Footer).unwrap();
+        assert!(!sidecar_path.exists());
+        drop(pf);
+        assert!(fs::metadata(&path).unwrap().len() > size);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Footer);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+        drop(pf);
+
+        // A crash while converting leaves both the sidecar file and the footer.
+        let data = fs::read(&path).unwrap();
+        fs::write(&sidecar_path, &data[size as usize..]).unwrap();
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Footer);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+        assert!(!sidecar_path.exists());
+
+        pf.set_storage(PartialStorage::Sidecar).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        drop(pf);
+
+        let mut pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Sidecar);
+        let mut buf = [0; 10];
+        pf.seek(SeekFrom::Start(BLOCK_LEN as u64)).unwrap();
+        pf.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1; 10]);
+
+        pf.complete_partial(&mut pf.lock_state()).unwrap();
+        assert!(!sidecar_path.exists());
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+
+    #[test]
+    fn checksums() {
+
+        /// A filler providing blocks filled with their index.
+        struct IndexFiller;
+        impl PartialFiller for IndexFiller {
+            fn provide<W: Write>(&self, block_index: u64, block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+                dest.write_all(&vec![block_index as u8; block_len]).map(Poll::Ready)
+            }
+        }
+
+        let path = temp_path("checksums");
+        let size = BLOCK_LEN as u64 * 4;
+        let block = |index: u8| vec![index; BLOCK_LEN];
+
+        {
+            let mut pf = PartialFile::create(&path, size, IndexFiller).unwrap();
+            pf.set_block_checksum(1, fletcher64(&block(1))).unwrap();
+            pf.set_block_checksum(2, fletcher64(&block(0))).unwrap();
+
+            // Provided block 1 is valid, block 2 is rejected.
+            let mut buf = [0; 10];
+            pf.seek(SeekFrom::Start(BLOCK_LEN as u64)).unwrap();
+            pf.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [1; 10]);
+            pf.seek(SeekFrom::Start(BLOCK_LEN as u64 * 2)).unwrap();
+            assert_eq!(pf.read(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+
+            // Written blocks are also checked.
+            assert_eq!(pf.write_at(BLOCK_LEN as u64 * 2, &block(2)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            pf.write_at(BLOCK_LEN as u64 * 2, &block(0)).unwrap();
+            pf.write_at(BLOCK_LEN as u64 * 3, &block(3)).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 4)]);
+            assert_eq!(pf.get_block_checksum(3), Some(fletcher64(&block(3))));
+        }
+
+        let mut pf = PartialFile::open(&path, IndexFiller).unwrap();
+        assert_eq!(pf.get_block_checksum(1), Some(fletcher64(&block(1))));
+        assert_eq!(pf.get_block_checksum(2), Some(fletcher64(&block(0))));
+        assert!(pf.verify_block(1).unwrap());
+        assert!(pf.verify_block(3).unwrap());
+        assert_eq!(pf.verify_block(0).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // Corrupt the data on disk.
+        pf.file.seek(SeekFrom::Start(BLOCK_LEN as u64 * 3)).unwrap();
+        pf.file.write_all(&[9]).unwrap();
+        assert!(!pf.verify_block(3).unwrap());
+
+        // A new checksum invalidates the filled block.
+        pf.set_block_checksum(1, 0).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(2, 4)]);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+
+    #[test]
+    fn merkle() {
+
+        /// A filler providing blocks from a full tree.
+        struct TreeFiller(Vec<Vec<u8>>, MerkleTree);
+        impl PartialFiller for TreeFiller {
+            fn provide<W: Write>(&self, block_index: u64, _block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+                dest.write_all(&self.0[block_index as usize]).map(Poll::Ready)
+            }
+            fn provide_proof(&self, block_index: u64) -> io::Result<Vec<MerkleHash>> {
+                Ok(self.1.proof(block_index).unwrap())
+            }
+        }
+
+        let path = temp_path("merkle");
+        let blocks = (0..5u8).map(|i| vec![i; BLOCK_LEN]).collect::<Vec<_>>();
+        let leaves = blocks.iter().map(|data| merkle_leaf(data)).collect::<Vec<_>>();
+        let full = MerkleTree::from_leaves(&leaves);
+        let size = BLOCK_LEN as u64 * 5;
+
+        {
+            let mut pf = PartialFile::create(&path, size, TreeFiller(blocks.clone(), full.clone())).unwrap();
+            let offset = BLOCK_LEN as u64;
+
+            assert_eq!(pf.write_block_verified(1, &blocks[1], &[]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            pf.set_merkle_root(full.root());
+
+            assert_eq!(pf.write_block_verified(1, &blocks[2], &full.proof(1).unwrap()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(pf.write_block_verified(1, &blocks[1], &full.proof(2).unwrap()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(pf.write_block_verified(1, &blocks[1][..10], &[]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            pf.write_block_verified(1, &blocks[1], &full.proof(1).unwrap()).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+
+            // The filler provides proofs.
+            let mut buf = [0; 10];
+            pf.seek(SeekFrom::Start(offset * 3)).unwrap();
+            pf.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [3; 10]);
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (3, 4)]);
+        }
+
+        let mut pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_merkle_tree().unwrap().root(), full.root());
+        assert_eq!(pf.get_merkle_tree().unwrap().proof(1), full.proof(1));
+
+        // Verified nodes are kept, block 0 is the sibling of block 1.
+        pf.write_block_verified(0, &blocks[0], &[]).unwrap();
+        // But the filler doesn't give proofs here.
+        let mut buf = [0; 10];
+        pf.seek(SeekFrom::Start(BLOCK_LEN as u64 * 4)).unwrap();
+        assert_eq!(pf.read(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 2), (3, 4)]);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn block_len() {
+
+        let path = temp_path("block-len");
+
+        {
+            let pf = PartialOptions::new()
+                .block_len(1000)
+                .create(&path, 4500, ())
+                .unwrap();
+            assert_eq!(pf.get_block_count(), 5);
+            pf.write_at(1000, &[1; 2000]).unwrap();
+            pf.write_at(4000, &[2; 500]).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3), (4, 5)]);
+        }
+
+        let mut pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_block_len(), 1000);
+        assert_eq!(pf.get_block_count(), 5);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3), (4, 5)]);
+
+        // The missing block is filled with zeros by the filler.
+        let mut buf = [1; 10];
+        pf.seek(SeekFrom::Start(3995)).unwrap();
+        pf.read_exact(&mut buf[..5]).unwrap();
+        assert_eq!(buf[..5], [0; 5]);
+        pf.seek(SeekFrom::Start(4000)).unwrap();
+        pf.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2; 10]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 5)]);
+
+        pf.read_block(0).unwrap();
+        assert!(pf.is_full());
+        drop(pf);
+
+        // A full file has no metadata, its block length is given to open it.
+        let pf = PartialOptions::new().block_len(1000).open(&path, ()).unwrap();
+        assert!(pf.is_full());
+        assert_eq!(pf.get_block_len(), 1000);
+        assert_eq!(pf.evict_blocks(0, 1).unwrap().get_ranges(), &[(0, 1)]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 5)]);
+        drop(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_block_len(), 1000);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 5)]);
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn block_set() {
+
+        let path = temp_path("block-set");
+        let size = BLOCK_LEN as u64 * 70;
+
+        let pf = PartialOptions::new()
+            .block_set(BlockSetKind::Bitmap)
+            .create(&path, size, ())
+            .unwrap();
+        assert_eq!(pf.get_block_set(), BlockSetKind::Bitmap);
+        pf.write_at(BLOCK_LEN as u64 * 63, &[1; BLOCK_LEN * 3]).unwrap();
+        pf.write_at(BLOCK_LEN as u64, &[2; BLOCK_LEN]).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (63, 66)]);
+        assert_eq!(pf.evict_blocks(64, 65).unwrap().get_ranges(), &[(64, 65)]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (63, 64), (65, 66)]);
+        pf.write_at(BLOCK_LEN as u64 * 64, &[1; BLOCK_LEN]).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (63, 66)]);
+        drop(pf);
+
+        // Files are opened with ranges by default, the representation can be changed.
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_block_set(), BlockSetKind::Ranges);
+        pf.set_block_set(BlockSetKind::Bitmap);
+        assert_eq!(pf.get_block_set(), BlockSetKind::Bitmap);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (63, 66)]);
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn read() {
+
+        let path = temp_path("read");
+        let block_len = 16;
+        let size = block_len * 5 + 7;
+        let expected = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+
+        let create = |fail| {
+            let pf = PartialOptions::new()
+                .block_len(block_len as u32)
+                .create(&path, size, OffsetFiller { block_len, fail })
+                .unwrap();
+            // Odd blocks are already filled, even ones are filled on read.
+            for block in (1..5).step_by(2) {
+                let offset = block * block_len;
+                pf.write_at(offset, &expected[offset as usize..(offset + block_len) as usize]).unwrap();
+            }
+            pf
+        };
+
+        for start in 0..=size {
+            let mut pf = create(None);
+            for end in start..=size {
+                let mut buf = vec![0; (end - start) as usize];
+                pf.seek(SeekFrom::Start(start)).unwrap();
+                pf.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, expected[start as usize..end as usize], "{start}..{end}");
+            }
+            assert_eq!(pf.read(&mut [0; 4]).unwrap(), 0);
+        }
+
+        let mut pf = create(None);
+        let mut bufs = [[0; 5], [0; 5], [0; 5], [0; 5]];
+        let mut slices = bufs.iter_mut().map(|buf| IoSliceMut::new(buf)).collect::<Vec<_>>();
+        pf.seek(SeekFrom::Start(12)).unwrap();
+        assert_eq!(pf.read_vectored(&mut slices).unwrap(), 20);
+        assert_eq!(bufs.concat(), expected[12..32]);
+
+        // Data before the failing block is returned, then the error.
+        let mut pf = create(Some(2));
+        let mut buf = [0; 32];
+        pf.seek(SeekFrom::Start(20)).unwrap();
+        assert_eq!(pf.read(&mut buf).unwrap(), 12);
+        assert_eq!(buf[..12], expected[20..32]);
+        assert_eq!(pf.read(&mut buf).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2), (3, 4)]);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn shared() {
+
+        let path = temp_path("shared");
+        let block_len = 64;
+        let size = block_len * 100 + 10;
+        let expected = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+
+        let pf = PartialOptions::new()
+            .block_len(block_len as u32)
+            .create(&path, size, OffsetFiller { block_len, fail: None })
+            .map(std::sync::Arc::new)
+            .unwrap();
+
+        let threads = (0..4u64).map(|i| {
+            let pf = pf.clone();
+            let expected = expected.clone();
+            std::thread::spawn(move || {
+                for block in (0..pf.get_block_count()).map(|block| (block + i * 25) % 101) {
+                    let offset = block * block_len;
+                    let data = pf.read_block(block).unwrap();
+                    assert_eq!(data, expected[offset as usize..(offset as usize + data.len())]);
+                    let mut buf = [0; 100];
+                    let len = pf.read_at(offset + 10, &mut buf).unwrap();
+                    assert_eq!(buf[..len], expected[offset as usize + 10..][..len]);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(pf.is_full());
+        assert_eq!(pf.read_block(101).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn pending() {
+
+        use std::sync::{mpsc, Arc, Mutex};
+
+        /// Defer every block to the test, through a channel.
+        struct DeferFiller(Mutex<mpsc::Sender<u64>>);
+
+        impl PartialFiller for DeferFiller {
+            fn provide<W: Write>(&self, block_index: u64, _block_len: usize, _dest: W) -> io::Result<Poll<()>> {
+                self.0.lock().unwrap().send(block_index).unwrap();
+                Ok(Poll::Pending)
+            }
+        }
+
+        let path = temp_path("pending");
+        let size = BLOCK_LEN as u64 * 3;
+        let (sender, receiver) = mpsc::channel();
+
+        let pf = Arc::new(PartialFile::create(&path, size, DeferFiller(Mutex::new(sender))).unwrap());
+
+        // Two readers of the same block, the filler is only called once.
+        let readers = (0..2).map(|_| {
+            let pf = pf.clone();
+            std::thread::spawn(move || pf.read_block(1))
+        }).collect::<Vec<_>>();
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert_eq!(pf.write_block(1, &[1; 10], &[]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        pf.write_block(1, &[1; BLOCK_LEN], &[]).unwrap();
+
+        for reader in readers {
+            assert_eq!(reader.join().unwrap().unwrap(), [1; BLOCK_LEN]);
+        }
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+
+        // A cancelled block resumes the reader with an error.
+        let reader = {
+            let pf = pf.clone();
+            std::thread::spawn(move || pf.read_block(2))
+        };
+
+        assert_eq!(receiver.recv().unwrap(), 2);
+        pf.cancel_block(2);
+        assert_eq!(reader.join().unwrap().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 2)]);
+
+        // Evicting blocks around a pending one keeps it pending, its reader
+        // is still resumed when cancelled.
+        let reader = {
+            let pf = pf.clone();
+            std::thread::spawn(move || pf.read_block(2))
+        };
+
+        assert_eq!(receiver.recv().unwrap(), 2);
+        assert_eq!(pf.evict_blocks(0, 3).unwrap().get_ranges(), &[(1, 2)]);
+        assert!(pf.lock_state().pending.contains(&2));
+        pf.cancel_block(2);
+        assert_eq!(reader.join().unwrap().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(receiver.try_recv().is_err());
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn pending_filled() {
+
+        use std::sync::{mpsc, Arc, Mutex};
+        use std::time::Duration;
+
+        /// Return pending for the first request only.
+        struct OnceFiller(Mutex<Option<mpsc::Sender<u64>>>);
+
+        impl PartialFiller for OnceFiller {
+            fn provide<W: Write>(&self, block_index: u64, block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+                match self.0.lock().unwrap().take() {
+                    Some(sender) => {
+                        sender.send(block_index).unwrap();
+                        Ok(Poll::Pending)
+                    }
+                    None => dest.write_all(&vec![1; block_len]).map(Poll::Ready)
+                }
+            }
+        }
+
+        let path = temp_path("pending-filled");
+        let size = BLOCK_LEN as u64 * 3;
+        let (sender, receiver) = mpsc::channel();
+
+        let pf = Arc::new(PartialFile::create(&path, size, OnceFiller(Mutex::new(Some(sender)))).unwrap());
+
+        // The pending block is filled by a write instead of the filler.
+        let reader = {
+            let pf = pf.clone();
+            std::thread::spawn(move || pf.read_block(1))
+        };
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        pf.write_at(BLOCK_LEN as u64, &[1; BLOCK_LEN]).unwrap();
+        assert_eq!(reader.join().unwrap().unwrap(), [1; BLOCK_LEN]);
+        assert!(pf.lock_state().pending.is_empty());
+
+        // Once evicted, the block is requested again.
+        pf.evict_blocks(1, 2).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let pf = pf.clone();
+            std::thread::spawn(move || sender.send(pf.read_block(1).unwrap()).unwrap());
+        }
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).unwrap(), [1; BLOCK_LEN]);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn readahead() {
+
+        use std::sync::Mutex;
+
+        /// Record requested blocks.
+        struct RecordFiller(Mutex<Vec<u64>>);
+
+        impl PartialFiller for RecordFiller {
+            fn provide<W: Write>(&self, block_index: u64, block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+                self.0.lock().unwrap().push(block_index);
+                dest.write_all(&vec![block_index as u8; block_len]).map(Poll::Ready)
+            }
+        }
+
+        let path = temp_path("readahead");
+        let size = 100;
+
+        let pf = PartialOptions::new()
+            .block_len(4)
+            .readahead(ReadaheadPolicy::Fixed(3))
+            .create(&path, size, RecordFiller(Mutex::new(Vec::new())))
+            .unwrap();
+
+        let take = || std::mem::take(&mut *pf.filler.0.lock().unwrap());
+
+        assert_eq!(pf.read_block(0).unwrap(), [0; 4]);
+        assert_eq!(take(), [0, 1, 2, 3]);
+        // Filled blocks are skipped.
+        pf.write_at(24, &[6; 4]).unwrap();
+        let mut buf = [0; 2];
+        pf.read_at(18, &mut buf).unwrap();
+        assert_eq!(buf, [4; 2]);
+        assert_eq!(take(), [4, 5, 7]);
+        // The window is clipped to the last block.
+        pf.read_block(24).unwrap();
+        assert_eq!(take(), [24]);
+
+        pf.set_readahead(ReadaheadPolicy::Adaptive(4));
+        for block in 10..14 {
+            pf.read_block(block).unwrap();
+        }
+        pf.read_block(20).unwrap();
+        assert_eq!(take(), [10, 11, 12, 13, 14, 15, 16, 17, 20]);
+
+        pf.set_readahead(ReadaheadPolicy::Adaptive(0));
+        for block in 8..10 {
+            pf.read_block(block).unwrap();
+        }
+        assert_eq!(take(), [8, 9]);
+
+        pf.set_readahead(ReadaheadPolicy::Off);
+        pf.read_block(22).unwrap();
+        assert_eq!(take(), [22]);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn provide_range() {
+
+        use std::sync::Mutex;
+
+        /// Record requested ranges, the block 5 is pending.
+        struct RangeFiller(Mutex<Vec<(u64, u64)>>);
+
+        impl PartialFiller for RangeFiller {
+
+            fn provide<W: Write>(&self, _block_index: u64, _block_len: usize, _dest: W) -> io::Result<Poll<()>> {
+                unreachable!()
+            }
+
+            fn provide_range<W: Write>(&self, from_block: u64, to_block: u64, _block_len: usize, len: u64, mut dest: W) -> io::Result<RangeVec<u64>> {
+                self.0.lock().unwrap().push((from_block, to_block));
+                let data = (0..len).map(|i| (from_block * 4 + i) as u8).collect::<Vec<_>>();
+                dest.write_all(&data)?;
+                let mut pending = RangeVec::new();
+                pending.push_range(5..6);
+                Ok(pending)
+            }
+
+        }
+
+        let path = temp_path("provide-range");
+        let size = 30;
+        let expected = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+
+        let pf = PartialOptions::new()
+            .block_len(4)
+            .readahead(ReadaheadPolicy::Fixed(1))
+            .create(&path, size, RangeFiller(Mutex::new(Vec::new())))
+            .unwrap();
+
+        pf.write_at(8, &expected[8..12]).unwrap();
+
+        let mut buf = [0; 17];
+        assert_eq!(pf.read_at(1, &mut buf).unwrap(), 17);
+        assert_eq!(buf, expected[1..18]);
+        assert_eq!(*pf.filler.0.lock().unwrap(), [(0, 2), (3, 6)]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 5)]);
+
+        // The last block is shorter.
+        assert_eq!(pf.read_block(7).unwrap(), expected[28..]);
+        assert_eq!(pf.filler.0.lock().unwrap().last(), Some(&(7, 8)));
+
+        pf.cancel_block(5);
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn mmap() {
+
+        let path = temp_path("mmap");
+        let size = BLOCK_LEN as u64 * 3 + 10;
+
+        let pf = PartialFile::create(&path, size, ()).unwrap();
+        pf.write_at(BLOCK_LEN as u64, &[1; BLOCK_LEN]).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 3, &[3; 10]).unwrap();
+
+        assert_eq!(pf.map_range(0, 10).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(pf.map_range(BLOCK_LEN as u64 * 2 - 10, 20).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(pf.map_range(size - 5, 10).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(pf.map_full().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(pf.map_range(0, 0).unwrap().is_empty());
+
+        {
+            let first = pf.map_range(BLOCK_LEN as u64 + 10, 100).unwrap();
+            let last = pf.map_range(BLOCK_LEN as u64 * 3, 10).unwrap();
+            assert_eq!(*first, [1; 100]);
+            assert_eq!(*last, [3; 10]);
+
+            // Filled blocks can't be modified, but missing ones can be filled.
+            assert_eq!(pf.write_at(BLOCK_LEN as u64 * 2 - 1, &[2; 2]).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+            assert_eq!(pf.set_block_checksum(1, 0).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+            pf.write_at(BLOCK_LEN as u64 * 2, &[2; BLOCK_LEN]).unwrap();
+            assert_eq!(*pf.map_range(BLOCK_LEN as u64 * 2, 10).unwrap(), [2; 10]);
+            assert_eq!(*first, [1; 100]);
+        }
+
+        pf.write_at(BLOCK_LEN as u64 * 2 - 1, &[2; 2]).unwrap();
+        drop(pf);
+
+        // Remove the footer to open it in full mode.
+        File::options().write(true).open(&path).unwrap().set_len(size).unwrap();
+        let pf = PartialFile::open(&path, ()).unwrap();
+        {
+            let full = pf.map_full().unwrap();
+            assert_eq!(full.len(), size as usize);
+            assert_eq!(full[BLOCK_LEN * 2 - 1..BLOCK_LEN * 2 + 1], [2; 2]);
+            assert_eq!(pf.write_at(0, &[1]).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+            pf.write_at(size, &[4; 10]).unwrap();
+        }
+        pf.write_at(0, &[1]).unwrap();
+        assert_eq!(pf.map_full().unwrap().len(), size as usize + 10);
+
+        drop(pf);
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn recover() {
+
+        let path = temp_path("recover");
+        let sidecar_path = PartialFile::<()>::calc_sidecar_path(&path);
+        let size = BLOCK_LEN as u64 * 6 + 10;
+
+        // Lose the footer, the file is then opened in full mode.
+        let pf = PartialFile::create(&path, size, ()).unwrap();
+        pf.write_at(BLOCK_LEN as u64, &[1; BLOCK_LEN * 2]).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 4 + 10, &[4; 10]).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 6, &[6; 10]).unwrap();
+        drop(pf);
+        File::options().write(true).open(&path).unwrap().set_len(size).unwrap();
+        assert!(PartialFile::open(&path, ()).unwrap().is_full());
+
+        let pf = PartialOptions::new().recover(&path, size, ()).unwrap();
+        let blocks = pf.get_partial_blocks().unwrap();
+        // Block 4 is only partially written, whether it's recovered depends on
+        // the part of it allocated by the filesystem.
+        assert!(blocks.contains(1) && blocks.contains(2) && blocks.contains(6));
+        assert!(!blocks.contains(0) && !blocks.contains(3) && !blocks.contains(5));
+        assert_eq!(pf.read_block(6).unwrap(), [6; 10]);
+        assert_eq!(pf.recover_blocks().unwrap(), RangeVec::new());
+        // Mismatching checksums given afterward invalidate recovered blocks.
+        pf.set_block_checksum(2, 0).unwrap();
+        assert!(!pf.get_partial_blocks().unwrap().contains(2));
+        drop(pf);
+
+        // A corrupted sidecar file is ignored if the footer is valid.
+        fs::write(&sidecar_path, b"corrupted").unwrap();
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Footer);
+        assert!(!sidecar_path.exists());
+        drop(pf);
+
+        // The footer is removed and replaced by a corrupted sidecar file.
+        File::options().write(true).open(&path).unwrap().set_len(size).unwrap();
+        fs::write(&sidecar_path, b"corrupted").unwrap();
+        assert!(PartialFile::open(&path, ()).is_err());
+
+        let pf = PartialOptions::new().storage(PartialStorage::Sidecar).recover(&path, size, ()).unwrap();
+        assert_eq!(pf.file.metadata().unwrap().len(), size);
+        assert!(pf.get_partial_blocks().unwrap().contains(2));
+
+        // Recovered blocks are cross-checked against known checksums.
+        {
+            let mut state = pf.lock_state();
+            let PartialMode::Partial { ref mut blocks } = state.mode else { panic!() };
+            blocks.remove(0, 7);
+            state.checksums.insert(1, 0);
+        }
+        let recovered = pf.recover_blocks().unwrap();
+        assert!(!recovered.contains(1) && recovered.contains(2) && recovered.contains(6));
+        drop(pf);
+
+        // Recovering with a footer removes the previous sidecar file.
+        drop(PartialOptions::new().recover(&path, size, ()).unwrap());
+        assert!(!sidecar_path.exists());
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_storage(), PartialStorage::Footer);
+        assert!(pf.get_partial_blocks().unwrap().contains(2));
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn journal() {
+
+        let path = temp_path("journal");
+        let journal_path = PartialFile::<()>::calc_journal_path(&path);
+        let size = BLOCK_LEN as u64 * 8;
+
+        // Blocks filled since the last checkpoint are replayed after a crash.
+        let pf = PartialFile::create(&path, size, ()).unwrap();
+        assert!(journal_path.exists());
+        pf.write_at(0, &[1; BLOCK_LEN]).unwrap();
+        pf.sync().unwrap();
+        pf.write_at(BLOCK_LEN as u64, &[2; BLOCK_LEN * 2]).unwrap();
+        pf.set_block_checksum(0, 0).unwrap();
+        pf.set_block_checksum(4, 4).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 7, &[7; BLOCK_LEN]).unwrap();
+        // The data of a journaled block is lost in a crash of the system.
+        pio::write_all_at(&pf.file, &[0; BLOCK_LEN], BLOCK_LEN as u64 * 7).unwrap();
+        mem::forget(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        let blocks = pf.get_partial_blocks().unwrap();
+        assert_eq!(blocks.get_ranges(), &[(1, 3)]);
+        assert_eq!(pf.get_block_checksum(4), Some(4));
+        assert_eq!(pf.get_block_checksum(2), Some(fletcher64(&[2; BLOCK_LEN])));
+        assert!(!pf.lock_state().dirty);
+        drop(pf);
+        assert!(!journal_path.exists());
+
+        // A crash while writing the footer is recovered from the checkpoint.
+        let pf = PartialFile::open(&path, ()).unwrap();
+        {
+            let mut state = pf.lock_state();
+            let footer_len = pf.file.metadata().unwrap().len() - size;
+            let mut footer = vec![0; footer_len as usize];
+            pio::read_exact_at(&pf.file, &mut footer, size).unwrap();
+            state.journal.as_mut().unwrap().checkpoint(PartialStorage::Footer, &footer).unwrap();
+        }
+        pf.write_at(BLOCK_LEN as u64 * 6, &[6; BLOCK_LEN]).unwrap();
+        pf.file.set_len(size + 10).unwrap();
+        mem::forget(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 3), (6, 7)]);
+        drop(pf);
+
+        // Checkpoints are made depending on the flush policy.
+        let pf = PartialFile::open(&path, ()).unwrap();
+        pf.set_flush_policy(FlushPolicy::Blocks(2));
+        pf.write_at(BLOCK_LEN as u64 * 7, &[7; BLOCK_LEN]).unwrap();
+        assert!(pf.lock_state().dirty);
+        pf.write_at(BLOCK_LEN as u64 * 3, &[3; BLOCK_LEN]).unwrap();
+        assert!(!pf.lock_state().dirty);
+        pf.set_flush_policy(FlushPolicy::Interval(Duration::ZERO));
+        pf.write_at(BLOCK_LEN as u64 * 4, &[4; BLOCK_LEN]).unwrap_err();
+        pf.set_block_checksum(4, fletcher64(&[4; BLOCK_LEN])).unwrap();
+        assert!(!pf.lock_state().dirty);
+        pf.write_at(BLOCK_LEN as u64 * 4, &[4; BLOCK_LEN]).unwrap();
+        assert!(!pf.lock_state().dirty);
+        mem::forget(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(1, 5), (6, 8)]);
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn set_size() {
+
+        let path = temp_path("set-size");
+        let filler = OffsetFiller { block_len: BLOCK_LEN as u64, fail: None };
+        let data = (0..BLOCK_LEN as u64 * 5).map(|i| i as u8).collect::<Vec<_>>();
+        let size = BLOCK_LEN as u64 * 3 + 10;
+
+        let pf = PartialFile::create(&path, size, filler).unwrap();
+        pf.write_at(0, &data[..size as usize]).unwrap();
+        pf.set_merkle_root([0; 32]);
+
+        {
+            let _map = pf.map_range(0, 10).unwrap();
+            assert_eq!(pf.set_size(0).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        }
+
+        // The last block is missing again when growing.
+        pf.set_size(BLOCK_LEN as u64 * 5).unwrap();
+        assert_eq!(pf.get_size(), BLOCK_LEN as u64 * 5);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 3)]);
+        assert_eq!(pf.get_block_checksum(3), None);
+        assert!(pf.get_merkle_tree().is_none());
+        let mut buf = vec![0; BLOCK_LEN * 5];
+        assert_eq!(pf.read_at(0, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, data);
+        assert!(pf.is_full());
+
+        // A full file stays full when shrinking, and becomes partial when growing.
+        pf.set_size(BLOCK_LEN as u64 * 2 + 5).unwrap();
+        assert!(pf.is_full());
+        assert_eq!(pf.file.metadata().unwrap().len(), BLOCK_LEN as u64 * 2 + 5);
+        pf.set_size(BLOCK_LEN as u64 * 4).unwrap();
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 2)]);
+        drop(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_size(), BLOCK_LEN as u64 * 4);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 2)]);
+
+        // The new last block stays filled when shrinking, with a new checksum,
+        // the file is then complete.
+        let size = BLOCK_LEN as u64 + 5;
+        pf.set_size(size).unwrap();
+        assert!(pf.is_full());
+        assert_eq!(pf.get_block_checksum(1), Some(fletcher64(&data[BLOCK_LEN..size as usize])));
+        assert_eq!(pf.get_block_checksum(2), None);
+        drop(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_size(), size);
+        assert_eq!(pf.read_block(1).unwrap(), data[BLOCK_LEN..size as usize]);
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn evict_blocks() {
+
+        let path = temp_path("evict-blocks");
+        let filler = OffsetFiller { block_len: BLOCK_LEN as u64, fail: None };
+        let data = (0..BLOCK_LEN as u64 * 5).map(|i| i as u8).collect::<Vec<_>>();
+        let size = BLOCK_LEN as u64 * 4 + 10;
+
+        let pf = PartialFile::create(&path, size, filler).unwrap();
+        pf.write_at(BLOCK_LEN as u64, &data[BLOCK_LEN..BLOCK_LEN * 4]).unwrap();
+        pf.set_block_checksum(2, fletcher64(&data[BLOCK_LEN * 2..BLOCK_LEN * 3])).unwrap();
+
+        {
+            let _map = pf.map_range(BLOCK_LEN as u64, 10).unwrap();
+            assert_eq!(pf.evict_blocks(0, 5).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        }
+
+        let evicted = pf.evict_blocks(0, 3).unwrap();
+        assert_eq!(evicted.get_ranges(), &[(1, 3)]);
+        assert!(!pf.lock_state().dirty);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(3, 4)]);
+        assert!(pf.get_block_checksum(2).is_some());
+        assert_eq!(pf.evict_blocks(0, 3).unwrap(), RangeVec::new());
+
+        #[cfg(target_os = "linux")]
+        for extent in pio::data_extents(&pf.file, 0, size).unwrap() {
+            assert!(extent.start >= BLOCK_LEN as u64 * 3);
+        }
+
+        // Evicted blocks are refilled when read.
+        let mut buf = vec![0; size as usize];
+        assert_eq!(pf.read_at(0, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, data[..size as usize]);
+
+        // A full file becomes partial again.
+        assert!(pf.is_full());
+        assert_eq!(pf.evict_blocks(4, 10).unwrap().get_ranges(), &[(4, 5)]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 4)]);
+        drop(pf);
+
+        let pf = PartialFile::open(&path, ()).unwrap();
+        assert_eq!(pf.get_size(), size);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 4)]);
+        assert_eq!(pf.read_block(3).unwrap(), data[BLOCK_LEN * 3..BLOCK_LEN * 4]);
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+    #[test]
+    fn complete() {
+
+        /// A filler providing blocks from a full tree, recording completions.
+        struct CompleteFiller(Vec<Vec<u8>>, MerkleTree, Mutex<Vec<io::Result<()>>>);
+        impl PartialFiller for CompleteFiller {
+            fn provide<W: Write>(&self, block_index: u64, _block_len: usize, mut dest: W) -> io::Result<Poll<()>> {
+                dest.write_all(&self.0[block_index as usize]).map(Poll::Ready)
+            }
+            fn provide_proof(&self, block_index: u64) -> io::Result<Vec<MerkleHash>> {
+                Ok(self.1.proof(block_index).unwrap())
+            }
+            fn completed(&self, result: io::Result<()>) {
+                self.2.lock().unwrap().push(result);
+            }
+        }
+
+        let path = temp_path("complete");
+        let journal_path = PartialFile::<()>::calc_journal_path(&path);
+        let blocks = (0..4u8).map(|i| vec![i; BLOCK_LEN]).collect::<Vec<_>>();
+        let leaves = blocks.iter().map(|data| merkle_leaf(data)).collect::<Vec<_>>();
+        let full = MerkleTree::from_leaves(&leaves);
+        let size = BLOCK_LEN as u64 * 4;
+        let filler = || CompleteFiller(blocks.clone(), full.clone(), Mutex::new(Vec::new()));
+
+        // The file is completed once all blocks are filled.
+        let pf = PartialFile::create(&path, size, filler()).unwrap();
+        for (index, data) in blocks.iter().enumerate().rev() {
+            assert!(pf.filler.2.lock().unwrap().is_empty());
+            pf.write_at(BLOCK_LEN as u64 * index as u64, data).unwrap();
+        }
+        assert!(pf.is_full());
+        assert!(matches!(pf.filler.2.lock().unwrap()[..], [Ok(())]));
+        assert_eq!(pf.file.metadata().unwrap().len(), size);
+        assert!(!journal_path.exists());
+        drop(pf);
+        assert!(PartialFile::open(&path, ()).unwrap().is_full());
+
+        // The whole file is verified against the Merkle root, blocks that are
+        // not verified are missing again.
+        let pf = PartialFile::create(&path, size, filler()).unwrap();
+        pf.set_merkle_root(full.root());
+        pf.write_block_verified(0, &blocks[0], &full.proof(0).unwrap()).unwrap();
+        pf.write_at(BLOCK_LEN as u64, &blocks[1]).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 2, &blocks[1]).unwrap();
+        pf.write_at(BLOCK_LEN as u64 * 3, &blocks[3]).unwrap();
+        // The proof of block 0 also verifies block 1, its sibling.
+        assert!(pf.is_partial());
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 2)]);
+        assert_eq!(pf.filler.2.lock().unwrap().pop().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(pf.get_block_checksum(2), None);
+
+        // Blocks given by the filler are verified with proofs.
+        let mut buf = vec![0; size as usize];
+        assert_eq!(pf.read_at(0, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, blocks.concat());
+        assert!(pf.is_full());
+        assert!(matches!(pf.filler.2.lock().unwrap()[..], [Ok(())]));
+        drop(pf);
+
+        // The verification is deferred while the file is mapped, because blocks
+        // can't become missing, until the next read.
+        let pf = PartialFile::create(&path, size, filler()).unwrap();
+        pf.set_merkle_root(full.root());
+        pf.write_at(0, &blocks[0]).unwrap();
+        {
+            let _map = pf.map_range(0, BLOCK_LEN).unwrap();
+            pf.write_at(BLOCK_LEN as u64, &blocks[2]).unwrap();
+            pf.write_at(BLOCK_LEN as u64 * 2, &blocks[2]).unwrap();
+            pf.write_at(BLOCK_LEN as u64 * 3, &blocks[3]).unwrap();
+            assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 4)]);
+            assert!(pf.filler.2.lock().unwrap().is_empty());
+        }
+        let mut buf = vec![0; BLOCK_LEN];
+        assert_eq!(pf.read_at(0, &mut buf).unwrap(), BLOCK_LEN);
+        assert_eq!(buf, blocks[0]);
+        assert_eq!(pf.get_partial_blocks().unwrap().get_ranges(), &[(0, 1)]);
+        assert_eq!(pf.filler.2.lock().unwrap().pop().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(pf);
+
+        // A file of size zero has no block and is complete once created.
+        let pf = PartialFile::create(&path, 0, filler()).unwrap();
+        assert!(pf.is_full());
+        assert!(matches!(pf.filler.2.lock().unwrap()[..], [Ok(())]));
+        assert_eq!(pf.file.metadata().unwrap().len(), 0);
+        assert!(!journal_path.exists());
+        drop(pf);
+
+        fs::remove_file(&path).unwrap();
+
+    }
+
+}
@@ -0,0 +1,278 @@
+//! Partial file footer format.
+//!
+//! The footer is placed right after the real data of a partial file, it is
+//! made of a variable-length body followed by a fixed-length trailer that
+//! is always at the very end of the file:
+//!
+//! ```text
+//! body:    size: u64, block_len: u32, flags: u32, blocks: RangeVec (varint)
+//!          [checksums: count (varint), (index delta (varint), checksum: u64)...]
+//!          [merkle: count (varint), (level: u8, index (varint), hash: [u8; 32])...]
+//! trailer: crc: u32, version: u16, reserved: u16, footer_len: u64, magic: [u8; 8]
+//! ```
+//!
+//! Optional sections of the body are present depending on the flags.
+//!
+//! All integers are little endian, the CRC-32 is computed over the body
+//! and the footer length includes both the body and the trailer. The same
+//! format is used for sidecar metadata files, the footer is then alone.
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::fmt;
+
+use byteorder::{WriteBytesExt, LE, ReadBytesExt};
+
+use crate::range::{self, RangeVec};
+
+use super::merkle::MerkleTree;
+
+
+/// Magic number at the very end of partial files.
+const MAGIC: [u8; 8] = *b"PEERFSPF";
+/// Current version of the footer format.
+const VERSION: u16 = 1;
+/// Length of the fixed trailer.
+const TRAILER_LEN: u64 = 24;
+
+/// Flag set when the body contains block checksums.
+const FLAG_CHECKSUMS: u32 = 0x1;
+/// Flag set when the body contains a Merkle tree.
+const FLAG_MERKLE: u32 = 0x2;
+/// All flags known by this version.
+const FLAGS_KNOWN: u32 = FLAG_CHECKSUMS | FLAG_MERKLE;
+
+
+/// Partial metadata stored in a footer.
+#[derive(Debug)]
+pub(crate) struct Footer<'a> {
+    /// Real size of the file, without the footer.
+    pub size: u64,
+    /// Length of the blocks, only the last one can be shorter.
+    pub block_len: u32,
+    /// Ranges of filled blocks.
+    pub blocks: Cow<'a, RangeVec<u64>>,
+    /// Known checksums of blocks.
+    pub checksums: Cow<'a, BTreeMap<u64, u64>>,
+    /// Merkle tree of the file with its verified nodes, if known.
+    pub merkle: Option<Cow<'a, MerkleTree>>,
+}
+
+impl<'a> Footer<'a> {
+
+    /// Write this footer, returning its total length.
+    pub fn write<W: Write>(&self, mut write: W) -> io::Result<u64> {
+
+        let mut body = Vec::new();
+        body.write_u64::<LE>(self.size)?;
+        body.write_u32::<LE>(self.block_len)?;
+
+        let mut flags = 0;
+        if !self.checksums.is_empty() {
+            flags |= FLAG_CHECKSUMS;
+        }
+        if self.merkle.is_some() {
+            flags |= FLAG_MERKLE;
+        }
+
+        body.write_u32::<LE>(flags)?;
+        self.blocks.write_varint(&mut body)?;
+
+        if flags & FLAG_CHECKSUMS != 0 {
+            range::write_varint_u64(&mut body, self.checksums.len() as u64)?;
+            let mut last_index = 0;
+            for (&index, &checksum) in self.checksums.iter() {
+                range::write_varint_u64(&mut body, index - last_index)?;
+                body.write_u64::<LE>(checksum)?;
+                last_index = index;
+            }
+        }
+
+        if let Some(merkle) = &self.merkle {
+            merkle.write(&mut body)?;
+        }
+
+        let footer_len = body.len() as u64 + TRAILER_LEN;
+
+        write.write_all(&body)?;
+        write.write_u32::<LE>(crc32fast::hash(&body))?;
+        write.write_u16::<LE>(VERSION)?;
+        write.write_u16::<LE>(0)?;
+        write.write_u64::<LE>(footer_len)?;
+        write.write_all(&MAGIC)?;
+
+        Ok(footer_len)
+
+    }
+
+    /// Read the footer at the end of the given file, `None` is returned if
+    /// there is no footer, errors related to the footer itself are returned
+    /// as invalid data IO errors wrapping a [`FooterError`].
+    pub fn read<R: Read + Seek>(read: R, file_len: u64) -> io::Result<Option<Footer<'static>>> {
+
+        let Some((footer, data_len)) = Self::read_inner(read, file_len)? else {
+            return Ok(None);
+        };
+
+        // The real size must exactly fit before the footer, if it is larger
+        // some data is missing.
+        if footer.size > data_len {
+            return Err(FooterError::Truncated.into());
+        } else if footer.size < data_len {
+            return Err(FooterError::Corrupt.into());
+        }
+
+        Ok(Some(footer))
+
+    }
+
+    /// Read a footer stored alone in a sidecar file, unlike [`Self::read`],
+    /// the footer is required and must fill the whole file. The size of the
+    /// data file must be checked by the caller with [`Self::check_data_len`].
+    pub fn read_sidecar<R: Read + Seek>(read: R, file_len: u64) -> io::Result<Footer<'static>> {
+        match Self::read_inner(read, file_len)? {
+            Some((footer, 0)) => Ok(footer),
+            _ => Err(FooterError::Corrupt.into())
+        }
+    }
+
+    /// Check that the given data file length is valid for this footer when
+    /// stored in a sidecar file.
+    pub fn check_data_len(&self, data_len: u64) -> io::Result<()> {
+        if data_len < self.size {
+            Err(FooterError::Truncated.into())
+        } else if data_len > self.size {
+            Err(FooterError::Corrupt.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Internal function to read the footer at the end of the given file,
+    /// also returning the length of the data before it.
+    fn read_inner<R: Read + Seek>(mut read: R, file_len: u64) -> io::Result<Option<(Footer<'static>, u64)>> {
+
+        if file_len < TRAILER_LEN {
+            return Ok(None);
+        }
+
+        read.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+        let crc = read.read_u32::<LE>()?;
+        let version = read.read_u16::<LE>()?;
+        let _reserved = read.read_u16::<LE>()?;
+        let footer_len = read.read_u64::<LE>()?;
+        let mut magic = [0; 8];
+        read.read_exact(&mut magic)?;
+
+        if magic != MAGIC {
+            return Ok(None);
+        } else if version != VERSION {
+            return Err(FooterError::UnknownVersion(version).into());
+        } else if footer_len < TRAILER_LEN || footer_len > file_len {
+            return Err(FooterError::Truncated.into());
+        }
+
+        let mut body = vec![0; (footer_len - TRAILER_LEN) as usize];
+        read.seek(SeekFrom::Start(file_len - footer_len))?;
+        read.read_exact(&mut body)?;
+
+        if crc32fast::hash(&body) != crc {
+            return Err(FooterError::Corrupt.into());
+        }
+
+        let footer = Self::read_body(&body).map_err(|_| FooterError::Corrupt)?;
+        Ok(Some((footer, file_len - footer_len)))
+
+    }
+
+    /// Internal function to parse the body of a footer, any error means that
+    /// the footer is corrupted.
+    fn read_body(body: &[u8]) -> io::Result<Footer<'static>> {
+
+        let mut cursor = Cursor::new(body);
+        let size = cursor.read_u64::<LE>()?;
+        let block_len = cursor.read_u32::<LE>()?;
+        let flags = cursor.read_u32::<LE>()?;
+        let blocks = RangeVec::read_varint(&mut cursor)?;
+
+        let mut checksums = BTreeMap::new();
+        if flags & FLAG_CHECKSUMS != 0 {
+            let count = range::read_varint_u64(&mut cursor)?;
+            let mut index = 0u64;
+            for i in 0..count {
+                let delta = range::read_varint_u64(&mut cursor)?;
+                // Indices are strictly increasing, except for the first one.
+                if i != 0 && delta == 0 {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                index = index.checked_add(delta).ok_or(io::ErrorKind::InvalidData)?;
+                checksums.insert(index, cursor.read_u64::<LE>()?);
+            }
+        }
+
+        let mut merkle = None;
+        if flags & FLAG_MERKLE != 0 && block_len != 0 {
+            let leaf_count = size.div_ceil(block_len as u64);
+            merkle = Some(Cow::Owned(MerkleTree::read(&mut cursor, leaf_count)?));
+        }
+
+        if block_len == 0 || flags & !FLAGS_KNOWN != 0 || cursor.position() != body.len() as u64 {
+            return Err(io::ErrorKind::InvalidData.into());
+        } else if let Some(&(_, to)) = blocks.get_ranges().last() {
+            if to > size.div_ceil(block_len as u64) {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+        }
+
+        Ok(Footer {
+            size,
+            block_len,
+            blocks: Cow::Owned(blocks),
+            checksums: Cow::Owned(checksums),
+            merkle
+        })
+
+    }
+
+}
+
+
+/// Errors specific to partial files' footer, these are returned wrapped in
+/// an [`io::Error`] of kind [`io::ErrorKind::InvalidData`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FooterError {
+    /// The footer or the data before it is shorter than expected.
+    Truncated,
+    /// The footer's checksum or content is invalid.
+    Corrupt,
+    /// The footer's version is not supported.
+    UnknownVersion(u16),
+}
+
+impl FooterError {
+
+    /// Get the footer error wrapped in the given IO error, if any.
+    pub fn from_io(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+
+}
+
+impl fmt::Display for FooterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FooterError::Truncated => f.write_str("truncated partial file footer"),
+            FooterError::Corrupt => f.write_str("corrupted partial file footer"),
+            FooterError::UnknownVersion(version) => write!(f, "unknown partial file footer version {version}"),
+        }
+    }
+}
+
+impl std::error::Error for FooterError {}
+
+impl From<FooterError> for io::Error {
+    fn from(err: FooterError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
@@ -0,0 +1,243 @@
+//! Partial file journal format.
+//!
+//! The journal is stored next to a partial file's data while it's open, it
+//! is made of a fixed header followed by records appended when the partial
+//! metadata is modified:
+//!
+//! ```text
+//! header: magic: [u8; 8], version: u16, reserved: u16
+//! record: kind: u8, len: u32, payload: [u8; len], crc: u32
+//! ```
+//!
+//! The CRC-32 is computed over the kind, the length and the payload. Add,
+//! remove and checksum records have a payload of two u64, a checkpoint
+//! record contains the storage (u8) and the whole footer being written.
+//! Records after a torn or corrupted one are ignored.
+//!
+//! All integers are little endian. The journal is reset to its header once
+//! the metadata has been durably written.
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::fs::File;
+
+use byteorder::{WriteBytesExt, LE, ReadBytesExt};
+
+use super::file::PartialStorage;
+use super::pio;
+
+
+/// Magic number at the start of journals.
+const MAGIC: [u8; 8] = *b"PEERFSPJ";
+/// Current version of the journal format.
+const VERSION: u16 = 1;
+/// Length of the fixed header.
+const HEADER_LEN: u64 = 12;
+
+const KIND_CHECKPOINT: u8 = 0x1;
+const KIND_ADD: u8 = 0x2;
+const KIND_REMOVE: u8 = 0x3;
+const KIND_CHECKSUM: u8 = 0x4;
+
+
+/// A change of the partial metadata.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Record {
+    /// The range of blocks has been filled.
+    Add { from: u64, to: u64 },
+    /// The range of blocks is missing again.
+    Remove { from: u64, to: u64 },
+    /// The checksum of a block is known.
+    Checksum { index: u64, checksum: u64 },
+}
+
+/// The content of a journal being replayed.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    /// The last checkpoint, with its storage and footer. If present, the
+    /// metadata may have been torn while being written and this footer
+    /// should be used instead.
+    pub checkpoint: Option<(PartialStorage, Vec<u8>)>,
+    /// Records appended after the last checkpoint, if any.
+    pub records: Vec<Record>,
+}
+
+/// An open journal file.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    /// Underlying file, only accessed with positional IO.
+    file: File,
+    /// Length of the valid part of the journal, where records are appended.
+    len: u64,
+}
+
+impl Journal {
+
+    /// Create a new empty journal, any existing one is overwritten.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut journal = Self { file, len: 0 };
+        journal.reset()?;
+        Ok(journal)
+
+    }
+
+    /// Open an existing journal and read its records, the journal is then
+    /// truncated after the last valid record. A journal with an invalid
+    /// header was torn while being created and is reset.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Replay)> {
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut journal = Self { file, len: 0 };
+        let mut replay = Replay::default();
+
+        if data.len() < HEADER_LEN as usize || data[..8] != MAGIC {
+            journal.reset()?;
+            return Ok((journal, replay));
+        }
+
+        let version = u16::from_le_bytes([data[8], data[9]]);
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown journal version"));
+        }
+
+        let mut pos = HEADER_LEN as usize;
+        while let Some((kind, payload)) = Self::read_entry(&data[pos..]) {
+            match Self::parse_entry(kind, payload) {
+                Some(Ok(record)) => replay.records.push(record),
+                Some(Err(checkpoint)) => {
+                    replay.checkpoint = Some(checkpoint);
+                    replay.records.clear();
+                }
+                None => break
+            }
+            pos += 9 + payload.len();
+        }
+
+        journal.len = pos as u64;
+        journal.file.set_len(journal.len)?;
+        Ok((journal, replay))
+
+    }
+
+    /// Append the given records, they are not synchronized.
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+
+        let mut buf = Vec::new();
+        for &record in records {
+            let (kind, a, b) = match record {
+                Record::Add { from, to } => (KIND_ADD, from, to),
+                Record::Remove { from, to } => (KIND_REMOVE, from, to),
+                Record::Checksum { index, checksum } => (KIND_CHECKSUM, index, checksum),
+            };
+            let mut payload = [0; 16];
+            payload[..8].copy_from_slice(&a.to_le_bytes());
+            payload[8..].copy_from_slice(&b.to_le_bytes());
+            Self::write_entry(&mut buf, kind, &payload)?;
+        }
+
+        self.append_raw(&buf)
+
+    }
+
+    /// Append a checkpoint with the footer being written and synchronize
+    /// the journal, previous records are superseded by this checkpoint.
+    pub fn checkpoint(&mut self, storage: PartialStorage, footer: &[u8]) -> io::Result<()> {
+
+        let mut payload = Vec::with_capacity(1 + footer.len());
+        payload.push(match storage {
+            PartialStorage::Footer => 0,
+            PartialStorage::Sidecar => 1,
+        });
+        payload.extend_from_slice(footer);
+
+        let mut buf = Vec::new();
+        Self::write_entry(&mut buf, KIND_CHECKPOINT, &payload)?;
+        self.append_raw(&buf)?;
+        self.file.sync_data()
+
+    }
+
+    /// Reset the journal to its header and synchronize it, once the metadata
+    /// has been durably written.
+    pub fn reset(&mut self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(&MAGIC);
+        header.write_u16::<LE>(VERSION)?;
+        header.write_u16::<LE>(0)?;
+        pio::write_all_at(&self.file, &header, 0)?;
+        self.file.set_len(HEADER_LEN)?;
+        self.len = HEADER_LEN;
+        self.file.sync_data()
+    }
+
+    /// Internal function to append raw entries, a failed append is later
+    /// overwritten.
+    fn append_raw(&mut self, buf: &[u8]) -> io::Result<()> {
+        pio::write_all_at(&self.file, buf, self.len)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Internal function to write an entry with its CRC.
+    fn write_entry(buf: &mut Vec<u8>, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let start = buf.len();
+        buf.write_u8(kind)?;
+        buf.write_u32::<LE>(payload.len() as u32)?;
+        buf.extend_from_slice(payload);
+        let crc = crc32fast::hash(&buf[start..]);
+        buf.write_u32::<LE>(crc)
+    }
+
+    /// Internal function to read an entry with a valid CRC, returning its
+    /// kind and payload.
+    fn read_entry(mut data: &[u8]) -> Option<(u8, &[u8])> {
+        let entry = data;
+        let kind = data.read_u8().ok()?;
+        let len = data.read_u32::<LE>().ok()? as usize;
+        let payload = data.get(..len)?;
+        let crc = (&data[len..]).read_u32::<LE>().ok()?;
+        (crc32fast::hash(&entry[..5 + len]) == crc).then_some((kind, payload))
+    }
+
+    /// Internal function to parse an entry, returning a record or a
+    /// checkpoint, `None` is returned if the entry is invalid.
+    fn parse_entry(kind: u8, payload: &[u8]) -> Option<Result<Record, (PartialStorage, Vec<u8>)>> {
+
+        if kind == KIND_CHECKPOINT {
+            let storage = match payload.first()? {
+                0 => PartialStorage::Footer,
+                1 => PartialStorage::Sidecar,
+                _ => return None
+            };
+            return Some(Err((storage, payload[1..].to_vec())));
+        }
+
+        let mut payload = <&[u8; 16]>::try_from(payload).ok()?.as_slice();
+        let a = payload.read_u64::<LE>().ok()?;
+        let b = payload.read_u64::<LE>().ok()?;
+
+        Some(Ok(match kind {
+            KIND_ADD => Record::Add { from: a, to: b },
+            KIND_REMOVE => Record::Remove { from: a, to: b },
+            KIND_CHECKSUM => Record::Checksum { index: a, checksum: b },
+            _ => return None
+        }))
+
+    }
+
+}
@@ -0,0 +1,326 @@
+//! Merkle tree over the blocks of a file.
+//!
+//! Leaves are the SHA-256 hashes of blocks' data prefixed by a zero byte,
+//! interior nodes are the SHA-256 hashes of their two children prefixed by
+//! a one byte. When a level has an odd number of nodes, the last one is
+//! promoted to the next level unchanged. Nodes are identified by their
+//! level, zero for leaves, and their index in the level.
+
+use std::io::{self, Read, Write};
+use std::collections::BTreeMap;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use crate::range;
+
+
+/// A SHA-256 hash of a Merkle tree node.
+pub type MerkleHash = [u8; 32];
+
+
+/// Compute the leaf hash of a block.
+pub fn merkle_leaf(data: &[u8]) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Compute the hash of an interior node from its children.
+fn merkle_node(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+
+/// A Merkle tree over blocks of a file, only the root and verified nodes
+/// are known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// Number of leaves, so blocks in the file.
+    leaf_count: u64,
+    /// Known nodes, including the root, by level and index.
+    nodes: BTreeMap<(u8, u64), MerkleHash>,
+}
+
+impl MerkleTree {
+
+    /// Create a tree where only the root is known.
+    pub fn new(root: MerkleHash, leaf_count: u64) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert((Self::calc_root_level(leaf_count), 0), root);
+        Self { leaf_count, nodes }
+    }
+
+    /// Create a fully known tree from all its leaves.
+    pub fn from_leaves(leaves: &[MerkleHash]) -> Self {
+
+        let mut nodes = BTreeMap::new();
+        let mut level_nodes = leaves.to_vec();
+        let mut level = 0;
+
+        if level_nodes.is_empty() {
+            level_nodes.push(merkle_leaf(&[]));
+        }
+
+        loop {
+            nodes.extend(level_nodes.iter().enumerate().map(|(i, &hash)| ((level, i as u64), hash)));
+            if level_nodes.len() == 1 {
+                break;
+            }
+            level_nodes = level_nodes.chunks(2).map(|pair| match pair {
+                [left, right] => merkle_node(left, right),
+                [single] => *single,
+                _ => unreachable!()
+            }).collect();
+            level += 1;
+        }
+
+        Self {
+            leaf_count: leaves.len() as u64,
+            nodes
+        }
+
+    }
+
+    #[inline]
+    pub fn leaf_count(&self) -> u64 {
+        self.leaf_count
+    }
+
+    /// Get the root hash, which identify the whole file.
+    #[inline]
+    pub fn root(&self) -> MerkleHash {
+        self.nodes[&(Self::calc_root_level(self.leaf_count), 0)]
+    }
+
+    /// Build the proof of the given leaf, that is the hashes of the siblings
+    /// from the leaf to the root, skipping levels where the node has no
+    /// sibling. `None` is returned if any sibling is unknown.
+    pub fn proof(&self, index: u64) -> Option<Vec<MerkleHash>> {
+        let mut proof = Vec::new();
+        self.walk(index, |level, _, sibling_idx| {
+            if let Some(sibling_idx) = sibling_idx {
+                proof.push(*self.nodes.get(&(level, sibling_idx))?);
+            }
+            Some(())
+        })?;
+        Some(proof)
+    }
+
+    /// Verify a leaf hash against the root, using the given proof for
+    /// siblings. The proof can be truncated if remaining siblings are known.
+    /// If valid, every node of the path is remembered as verified, so that
+    /// next proofs can be shorter.
+    pub fn verify(&mut self, index: u64, leaf: MerkleHash, proof: &[MerkleHash]) -> bool {
+
+        if index >= self.leaf_count {
+            return false;
+        }
+
+        let mut path = Vec::new();
+        let mut proof = proof.iter();
+        let mut hash = leaf;
+
+        let mut idx = index;
+        let mut len = self.leaf_count;
+        let mut level = 0;
+
+        loop {
+
+            if let Some(known) = self.nodes.get(&(level, idx)) {
+                // We reached a verified node, the root in the worst case.
+                if *known != hash {
+                    return false;
+                }
+                break;
+            } else if len == 1 {
+                return false;
+            }
+
+            path.push(((level, idx), hash));
+
+            let sibling_idx = idx ^ 1;
+            if sibling_idx < len {
+                let sibling = match proof.next().or_else(|| self.nodes.get(&(level, sibling_idx))) {
+                    Some(sibling) => *sibling,
+                    None => return false
+                };
+                path.push(((level, sibling_idx), sibling));
+                hash = if idx & 1 == 0 {
+                    merkle_node(&hash, &sibling)
+                } else {
+                    merkle_node(&sibling, &hash)
+                };
+            }
+
+            idx /= 2;
+            len = len.div_ceil(2);
+            level += 1;
+
+        }
+
+        self.nodes.extend(path);
+        true
+
+    }
+
+    /// Internal function to walk the path from a leaf to the root, calling
+    /// the given function with the level, index and sibling index, if any,
+    /// of each node except the root.
+    fn walk<F>(&self, index: u64, mut func: F) -> Option<()>
+    where
+        F: FnMut(u8, u64, Option<u64>) -> Option<()>
+    {
+
+        if index >= self.leaf_count {
+            return None;
+        }
+
+        let mut idx = index;
+        let mut len = self.leaf_count;
+        let mut level = 0;
+
+        while len > 1 {
+            let sibling_idx = idx ^ 1;
+            func(level, idx, (sibling_idx < len).then_some(sibling_idx))?;
+            idx /= 2;
+            len = len.div_ceil(2);
+            level += 1;
+        }
+
+        Some(())
+
+    }
+
+    /// Write the root and known nodes of this tree.
+    pub(crate) fn write<W: Write>(&self, mut write: W) -> io::Result<()> {
+        range::write_varint_u64(&mut write, self.nodes.len() as u64)?;
+        for (&(level, idx), hash) in &self.nodes {
+            write.write_u8(level)?;
+            range::write_varint_u64(&mut write, idx)?;
+            write.write_all(hash)?;
+        }
+        Ok(())
+    }
+
+    /// Read a tree written with [`Self::write`], the leaf count is not
+    /// written and must be given.
+    pub(crate) fn read<R: Read>(mut read: R, leaf_count: u64) -> io::Result<Self> {
+
+        let root_level = Self::calc_root_level(leaf_count);
+        let count = range::read_varint_u64(&mut read)?;
+        let mut nodes = BTreeMap::new();
+
+        for _ in 0..count {
+
+            let level = read.read_u8()?;
+            let idx = range::read_varint_u64(&mut read)?;
+            let mut hash = [0; 32];
+            read.read_exact(&mut hash)?;
+
+            if level > root_level || idx >= Self::calc_level_len(leaf_count, level) {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+
+            nodes.insert((level, idx), hash);
+
+        }
+
+        if !nodes.contains_key(&(root_level, 0)) {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+
+        Ok(Self { leaf_count, nodes })
+
+    }
+
+    /// Number of nodes in the given level.
+    fn calc_level_len(leaf_count: u64, level: u8) -> u64 {
+        let mut len = leaf_count.max(1);
+        for _ in 0..level {
+            len = len.div_ceil(2);
+        }
+        len
+    }
+
+    /// Level of the root.
+    fn calc_root_level(leaf_count: u64) -> u8 {
+        let mut len = leaf_count.max(1);
+        let mut level = 0;
+        while len > 1 {
+            len = len.div_ceil(2);
+            level += 1;
+        }
+        level
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn proofs() {
+
+        for leaf_count in 1..20u64 {
+
+            let leaves = (0..leaf_count).map(|i| merkle_leaf(&i.to_le_bytes())).collect::<Vec<_>>();
+            let full = MerkleTree::from_leaves(&leaves);
+            assert_eq!(full.leaf_count(), leaf_count);
+
+            let mut tree = MerkleTree::new(full.root(), leaf_count);
+
+            for (index, &leaf) in leaves.iter().enumerate() {
+                let index = index as u64;
+                let proof = full.proof(index).unwrap();
+                // Check invalid leaves and proofs on a tree knowing only the root.
+                let mut fresh = MerkleTree::new(full.root(), leaf_count);
+                assert!(!fresh.verify(index, merkle_leaf(b"invalid"), &proof));
+                if let Some(first) = proof.first() {
+                    let mut invalid_proof = proof.clone();
+                    invalid_proof[0] = merkle_leaf(&first[..]);
+                    assert!(!fresh.verify(index, leaf, &invalid_proof));
+                }
+                assert!(fresh.verify(index, leaf, &proof));
+                assert!(tree.verify(index, leaf, &proof));
+                // Once verified, the leaf itself is known.
+                assert!(tree.verify(index, leaf, &[]));
+            }
+
+            // All nodes are now known.
+            assert_eq!(tree, full);
+
+        }
+
+    }
+
+    #[test]
+    fn truncated_proof() {
+
+        let leaves = (0..8u64).map(|i| merkle_leaf(&i.to_le_bytes())).collect::<Vec<_>>();
+        let full = MerkleTree::from_leaves(&leaves);
+        let mut tree = MerkleTree::new(full.root(), 8);
+
+        assert!(!tree.verify(0, leaves[0], &[]));
+        assert!(tree.verify(0, leaves[0], &full.proof(0).unwrap()));
+        // The sibling leaf and its uncles are now known.
+        assert!(tree.verify(1, leaves[1], &[]));
+        assert!(!tree.verify(2, leaves[2], &[]));
+        assert!(tree.verify(2, leaves[2], &full.proof(2).unwrap()[..1]));
+
+        let mut buf = Vec::new();
+        tree.write(&mut buf).unwrap();
+        assert_eq!(MerkleTree::read(&buf[..], 8).unwrap(), tree);
+        assert!(MerkleTree::read(&buf[..], 4).is_err());
+
+    }
+
+}
@@ -8,8 +8,18 @@ use std::io::{self};
 
 
 mod file;
+mod block;
+mod footer;
+mod checksum;
+mod merkle;
+mod journal;
+mod pio;
 
 pub use file::*;
+pub use block::*;
+pub use checksum::*;
+pub use merkle::*;
+pub use footer::FooterError;
 
 
 
@@ -0,0 +1,139 @@
+//! Positional IO on files, these functions never use the file's cursor and
+//! can therefore be used concurrently on a shared file.
+
+use std::fs::File;
+use std::ops::Range;
+use std::io;
+
+
+/// Read data at the given offset, returning the number of bytes read.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Read data at the given offset, returning the number of bytes read.
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Write data at the given offset, returning the number of bytes written.
+#[cfg(unix)]
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+/// Write data at the given offset, returning the number of bytes written.
+#[cfg(windows)]
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+/// Read exactly enough data at the given offset to fill the buffer.
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                buf = &mut buf[len..];
+                offset += len as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Write the whole buffer at the given offset.
+pub fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match write_at(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                buf = &buf[len..];
+                offset += len as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Return the ranges of data in the given range of the file, anything else
+/// is a hole that is not allocated on disk. Unlike other functions, this
+/// moves the cursor of the file.
+#[cfg(target_os = "linux")]
+pub fn data_extents(file: &File, from: u64, to: u64) -> io::Result<Vec<Range<u64>>> {
+
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence| {
+        // SAFETY: The file descriptor is valid as long as the file is.
+        match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+            -1 => Err(io::Error::last_os_error()),
+            offset => Ok(offset as u64)
+        }
+    };
+
+    let mut extents = Vec::new();
+    let mut offset = from;
+
+    while offset < to {
+
+        let data = match seek(offset, libc::SEEK_DATA) {
+            Ok(data) if data < to => data,
+            Ok(_) => break,
+            // There is no data after the offset.
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(err) => return Err(err)
+        };
+
+        // There is always a virtual hole at the end of the file.
+        let hole = seek(data, libc::SEEK_HOLE)?.min(to);
+        extents.push(data..hole);
+        offset = hole;
+
+    }
+
+    Ok(extents)
+
+}
+
+/// Return the ranges of data in the given range of the file, holes can't be
+/// found on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_file: &File, _from: u64, _to: u64) -> io::Result<Vec<Range<u64>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "holes can't be found on this platform"))
+}
+
+/// Deallocate the given range of the file without changing its size, the
+/// range then reads as zeros. If the filesystem doesn't support it, the
+/// range is left as-is.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+
+    use std::os::unix::io::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: The file descriptor is valid as long as the file is.
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+
+}
+
+/// Deallocate the given range of the file without changing its size, this
+/// is not supported on this platform and the range is left as-is.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
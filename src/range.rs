@@ -1,10 +1,13 @@
 //! Auto-merging range vector.
 
 
+use std::ops::{Add, Bound, Range, RangeBounds, Sub};
+use std::io::{self, Read, Write};
 use std::cmp::Ordering;
-use std::fmt;
+use std::{fmt, slice, vec};
 
 
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct RangeVec<T> {
     data: Vec<(T, T)>
 }
@@ -130,12 +133,255 @@ where
 
     }
 
+    pub fn remove(&mut self, from: T, to: T) {
+
+        assert!(to > from, "Invalid range.");
+
+        let (start_idx, end_idx) = self.find_overlapping(from, to);
+
+        if start_idx >= end_idx {
+            // No range intersect with the removed one.
+            return;
+        }
+
+        // These are safe because 'start_idx < end_idx <= size'.
+        let &(first_from, _) = unsafe { self.data.get_unchecked(start_idx) };
+        let &(_, last_to) = unsafe { self.data.get_unchecked(end_idx - 1) };
+
+        // If the first or last intersecting ranges are overflowing the removed
+        // range, we keep their remaining parts, this splits a range in two if
+        // the removed range is strictly contained in it.
+        let head = (first_from < from).then_some((first_from, from));
+        let tail = (last_to > to).then_some((to, last_to));
+
+        self.data.splice(start_idx..end_idx, head.into_iter().chain(tail));
+
+    }
+
+    /// Return a new range vector containing values that are in either `self` or `other`.
+    pub fn union(&self, other: &Self) -> Self {
+        let capacity = self.data.len() + other.data.len();
+        let data = Self::merge_sorted(capacity, self.data.iter().copied(), other.data.iter().copied());
+        Self { data }
+    }
+
+    /// Merge all ranges of `other` into this range vector, this is equivalent
+    /// to pushing all ranges but it's done in a single linear pass.
+    pub fn merge(&mut self, other: &Self) {
+        let capacity = self.data.len() + other.data.len();
+        let data = std::mem::take(&mut self.data);
+        self.data = Self::merge_sorted(capacity, data.into_iter(), other.data.iter().copied());
+    }
+
+    /// Push all ranges of the given iterator in a single linear pass. Ranges
+    /// must be sorted by their start, but they can intersect.
+    pub fn extend_sorted<I: IntoIterator<Item = (T, T)>>(&mut self, iter: I) {
+
+        let mut last_from = None;
+        let iter = iter.into_iter().inspect(move |&(from, to)| {
+            assert!(to > from, "Invalid range.");
+            if let Some(last_from) = last_from {
+                assert!(from >= last_from, "Unsorted range.");
+            }
+            last_from = Some(from);
+        });
+
+        // Ranges are merged into a new vector, so this vector is left
+        // unchanged if the iterator panics.
+        let capacity = self.data.len() + iter.size_hint().0;
+        self.data = Self::merge_sorted(capacity, self.data.iter().copied(), iter);
+
+    }
+
+    /// Internal function to merge two iterators of ranges sorted by their start
+    /// into a new raw ranges vector.
+    fn merge_sorted<A, B>(capacity: usize, a: A, b: B) -> Vec<(T, T)>
+    where
+        A: Iterator<Item = (T, T)>,
+        B: Iterator<Item = (T, T)>
+    {
+
+        let mut data = Vec::with_capacity(capacity);
+
+        let mut a = a.peekable();
+        let mut b = b.peekable();
+
+        loop {
+            // Always take the range with the lowest start first, so we just
+            // need to merge it with the last range of the new vector.
+            let range = match (a.peek(), b.peek()) {
+                (Some(&(a_from, _)), Some(&(b_from, _))) => {
+                    if a_from <= b_from {
+                        a.next()
+                    } else {
+                        b.next()
+                    }
+                }
+                (Some(_), None) => a.next(),
+                (None, Some(_)) => b.next(),
+                (None, None) => break
+            };
+            Self::push_last(&mut data, range.unwrap());
+        }
+
+        data
+
+    }
+
+    /// Return a new range vector containing values that are in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+
+        let mut data = Vec::new();
+
+        let mut self_idx = 0;
+        let mut other_idx = 0;
+
+        while let (Some(&(self_from, self_to)), Some(&(other_from, other_to))) =
+            (self.data.get(self_idx), other.data.get(other_idx)) {
+
+            let from = self_from.max(other_from);
+            let to = self_to.min(other_to);
+            if from < to {
+                data.push((from, to));
+            }
+
+            // The range ending first can't intersect with any further range.
+            if self_to <= other_to {
+                self_idx += 1;
+            } else {
+                other_idx += 1;
+            }
+
+        }
+
+        Self { data }
+
+    }
+
+    /// Return a new range vector containing values that are in `self` but not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+
+        let mut data = Vec::with_capacity(self.data.len());
+
+        // Index of the first range of 'other' that may intersect with the
+        // current range of 'self', it only increase.
+        let mut other_idx = 0;
+
+        for &(from, to) in &self.data {
+
+            let mut from = from;
+
+            while let Some(&(other_from, other_to)) = other.data.get(other_idx) {
+                if other_to <= from {
+                    // This range is before the current one, it can't
+                    // intersect any further range of 'self'.
+                    other_idx += 1;
+                } else if other_from >= to {
+                    // This range is after the current one, but it may
+                    // intersect with next ranges of 'self'.
+                    break;
+                } else {
+                    if other_from > from {
+                        data.push((from, other_from));
+                    }
+                    if other_to >= to {
+                        // The removed range overflow the current one, we
+                        // keep it because it may intersect next ranges.
+                        from = to;
+                        break;
+                    }
+                    from = other_to;
+                    other_idx += 1;
+                }
+            }
+
+            if from < to {
+                data.push((from, to));
+            }
+
+        }
+
+        Self { data }
+
+    }
+
+    /// Compute the difference from an `old` version of this range vector,
+    /// returning the ranges added and removed since then, in this order.
+    /// Applying them to `old` with [`Self::apply_diff`] gives `self` back.
+    pub fn diff(&self, old: &Self) -> (Self, Self) {
+        (self.difference(old), old.difference(self))
+    }
+
+    /// Apply a difference computed by [`Self::diff`], removed ranges are
+    /// removed before added ones are merged.
+    pub fn apply_diff(&mut self, added: &Self, removed: &Self) {
+        if !removed.data.is_empty() {
+            *self = self.difference(removed);
+        }
+        self.merge(added);
+    }
+
+    /// Return a new range vector containing values between `from` and `to`
+    /// that are not in `self`.
+    pub fn complement_within(&self, from: T, to: T) -> Self {
+        assert!(to > from, "Invalid range.");
+        Self { data: vec![(from, to)] }.difference(self)
+    }
+
+    /// Internal function to push a range at the end of a raw ranges vector,
+    /// merging it with the last range if they intersect. The range's start
+    /// must not be lower than the start of the last range.
+    fn push_last(data: &mut Vec<(T, T)>, range: (T, T)) {
+        let (from, to) = range;
+        if let Some((_, last_to)) = data.last_mut() {
+            if from <= *last_to {
+                if to > *last_to {
+                    *last_to = to;
+                }
+                return;
+            }
+        }
+        data.push(range);
+    }
+
     #[inline]
     pub fn get_ranges(&self) -> &[(T, T)] {
         &self.data[..]
     }
 
     pub fn contains(&self, value: T) -> bool {
+        self.find(value).is_ok()
+    }
+
+    /// Return the slice of ranges that intersect with the given range.
+    pub fn overlapping(&self, from: T, to: T) -> &[(T, T)] {
+        let (start_idx, end_idx) = self.find_overlapping(from, to);
+        &self.data[start_idx..end_idx.max(start_idx)]
+    }
+
+    /// Return an iterator over all missing sub-ranges between `from` and `to`.
+    pub fn gaps(&self, from: T, to: T) -> Gaps<'_, T> {
+        Gaps {
+            ranges: self.overlapping(from, to),
+            cursor: from,
+            to
+        }
+    }
+
+    /// Return the first value greater or equal to `value` that is missing.
+    pub fn next_missing_after(&self, value: T) -> T {
+        match self.find(value) {
+            // The end of a range is always missing because ranges are never
+            // contiguous.
+            Ok(found_idx) => unsafe { self.data.get_unchecked(found_idx) }.1,
+            Err(_) => value
+        }
+    }
+
+    /// Internal function to binary search the range containing the given value,
+    /// if not found, the index where a range starting at this value should be
+    /// inserted is returned.
+    fn find(&self, value: T) -> Result<usize, usize> {
         self.data.binary_search_by(move |&(item_from, item_to)| {
             if value < item_from {
                 Ordering::Greater
@@ -144,9 +390,275 @@ where
             } else {
                 Ordering::Equal
             }
-        }).is_ok()
+        })
+    }
+
+    /// Internal function to binary search the bounds of ranges intersecting
+    /// with the given range. The start index is the first range ending after
+    /// 'from' and the end index is the first range starting at or after 'to'.
+    /// If no range intersect, the end index is less or equal to start index.
+    fn find_overlapping(&self, from: T, to: T) -> (usize, usize) {
+        let start_idx = self.data.partition_point(|&(_, item_to)| item_to <= from);
+        let end_idx = self.data.partition_point(|&(item_from, _)| item_from < to);
+        (start_idx, end_idx)
+    }
+
+}
+
+impl<T> RangeVec<T>
+where
+    T: Ord + Copy + Default
+{
+
+    /// Return the first missing value, starting from the default value of
+    /// `T` (zero for integers).
+    #[inline]
+    pub fn first_missing(&self) -> T {
+        self.next_missing_after(T::default())
+    }
+
+}
+
+impl<T> RangeVec<T>
+where
+    T: Ord + Copy + Default + Add<Output = T> + Sub<Output = T>
+{
+
+    /// Return the sum of the lengths of all ranges.
+    pub fn covered_len(&self) -> T {
+        self.data.iter().fold(T::default(), |len, &(from, to)| len + (to - from))
+    }
+
+}
+
+impl<T> RangeVec<T>
+where
+    T: Integer
+{
+
+    /// Push any kind of range, unbounded start and end are respectively
+    /// replaced by the minimum and maximum values of `T`. Note that the
+    /// maximum value itself can't be contained, so this panics if the end is
+    /// included at this value. Empty ranges are ignored.
+    pub fn push_range<R: RangeBounds<T>>(&mut self, range: R) {
+
+        let from = match range.start_bound() {
+            Bound::Included(&from) => from,
+            Bound::Excluded(&from) => match from.checked_succ() {
+                Some(from) => from,
+                None => return
+            },
+            Bound::Unbounded => T::MIN
+        };
+
+        let to = match range.end_bound() {
+            Bound::Included(&to) => to.checked_succ().expect("Maximum value can't be contained."),
+            Bound::Excluded(&to) => to,
+            Bound::Unbounded => T::MAX
+        };
+
+        if to > from {
+            self.push(from, to);
+        }
+
+    }
+
+    /// Return an iterator over every single value contained in ranges.
+    pub fn iter_values(&self) -> impl Iterator<Item = T> + '_
+    where
+        Range<T>: Iterator<Item = T>
+    {
+        self.data.iter().flat_map(|&(from, to)| from..to)
+    }
+
+}
+
+impl RangeVec<u64> {
+
+    /// Write this range vector in a compact form. Ranges count is written
+    /// first, then each range is written as the delta between its start
+    /// and the previous range's end, followed by its length. All integers
+    /// are LEB128 variable-length encoded.
+    pub fn write_varint<W: Write>(&self, mut write: W) -> io::Result<()> {
+        write_varint_u64(&mut write, self.data.len() as u64)?;
+        let mut last_to = 0;
+        for &(from, to) in &self.data {
+            write_varint_u64(&mut write, from - last_to)?;
+            write_varint_u64(&mut write, to - from)?;
+            last_to = to;
+        }
+        Ok(())
+    }
+
+    /// Read a range vector written by [`Self::write_varint`]. Ranges are
+    /// validated like with [`Self::from_raw`], an invalid data error is
+    /// returned if ranges are empty, contiguous or overflowing.
+    pub fn read_varint<R: Read>(mut read: R) -> io::Result<Self> {
+
+        let count = read_varint_u64(&mut read)?;
+
+        // The count is not trusted here, so we limit the initial capacity.
+        let mut data = Vec::with_capacity(count.min(1024) as usize);
+        let mut last_to = 0u64;
+
+        for _ in 0..count {
+            let delta = read_varint_u64(&mut read)?;
+            let len = read_varint_u64(&mut read)?;
+            let from = last_to.checked_add(delta).ok_or(io::ErrorKind::InvalidData)?;
+            let to = from.checked_add(len).ok_or(io::ErrorKind::InvalidData)?;
+            data.push((from, to));
+            last_to = to;
+        }
+
+        Self::from_raw(data).ok_or_else(|| io::ErrorKind::InvalidData.into())
+
+    }
+
+}
+
+/// Write a LEB128 variable-length unsigned integer.
+pub(crate) fn write_varint_u64<W: Write>(mut write: W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        } else {
+            buf[len] = byte | 0x80;
+            len += 1;
+        }
+    }
+    write.write_all(&buf[..len])
+}
+
+/// Read a LEB128 variable-length unsigned integer, returning an invalid data
+/// error if it overflows 64 bits.
+pub(crate) fn read_varint_u64<R: Read>(mut read: R) -> io::Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0; 1];
+        read.read_exact(&mut byte)?;
+        let byte = byte[0];
+        let bits = (byte & 0x7F) as u64;
+        if (shift == 63 && bits > 1) || shift > 63 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Iterator over missing sub-ranges of a [`RangeVec`], see [`RangeVec::gaps`].
+pub struct Gaps<'a, T> {
+    /// Remaining ranges intersecting with the iterated range.
+    ranges: &'a [(T, T)],
+    /// Start of the next gap.
+    cursor: T,
+    /// End of the iterated range.
+    to: T
+}
+
+impl<'a, T> Iterator for Gaps<'a, T>
+where
+    T: Ord + Copy
+{
+
+    type Item = (T, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.cursor < self.to {
+            match self.ranges.split_first() {
+                Some((&(from, to), ranges)) => {
+                    self.ranges = ranges;
+                    let gap_from = self.cursor;
+                    self.cursor = to;
+                    if from > gap_from {
+                        return Some((gap_from, from));
+                    }
+                }
+                None => {
+                    let gap_from = self.cursor;
+                    self.cursor = self.to;
+                    return Some((gap_from, self.to));
+                }
+            }
+        }
+        None
+    }
+
+}
+
+impl<T> Default for RangeVec<T>
+where
+    T: Ord + Copy
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Extend<(T, T)> for RangeVec<T>
+where
+    T: Ord + Copy
+{
+    fn extend<I: IntoIterator<Item = (T, T)>>(&mut self, iter: I) {
+        for (from, to) in iter {
+            self.push(from, to);
+        }
+    }
+}
+
+impl<T> Extend<Range<T>> for RangeVec<T>
+where
+    T: Ord + Copy
+{
+    /// Extend this range vector with standard ranges, empty ones are ignored.
+    fn extend<I: IntoIterator<Item = Range<T>>>(&mut self, iter: I) {
+        for range in iter {
+            if range.end > range.start {
+                self.push(range.start, range.end);
+            }
+        }
+    }
+}
+
+impl<T, R> FromIterator<R> for RangeVec<T>
+where
+    T: Ord + Copy,
+    Self: Extend<R>
+{
+    fn from_iter<I: IntoIterator<Item = R>>(iter: I) -> Self {
+        let mut vec = Self::new();
+        vec.extend(iter);
+        vec
+    }
+}
+
+impl<T> IntoIterator for RangeVec<T> {
+    type Item = (T, T);
+    type IntoIter = vec::IntoIter<(T, T)>;
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.into_iter()
     }
+}
 
+impl<'a, T> IntoIterator for &'a RangeVec<T> {
+    type Item = &'a (T, T);
+    type IntoIter = slice::Iter<'a, (T, T)>;
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.iter()
+    }
 }
 
 impl<T> fmt::Debug for RangeVec<T>
@@ -158,6 +670,28 @@ where
     }
 }
 
+/// Integer types usable with [`RangeVec::push_range`] and [`RangeVec::iter_values`].
+pub trait Integer: Ord + Copy {
+    const MIN: Self;
+    const MAX: Self;
+    fn checked_succ(self) -> Option<Self>;
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(impl Integer for $ty {
+            const MIN: Self = <$ty>::MIN;
+            const MAX: Self = <$ty>::MAX;
+            #[inline]
+            fn checked_succ(self) -> Option<Self> {
+                self.checked_add(1)
+            }
+        })*
+    };
+}
+
+impl_integer!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
 #[cfg(test)]
 mod tests {
 
@@ -200,4 +734,343 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn remove() {
+
+        let mut vec = RangeVec::new();
+
+        vec.push(0, 100);
+        vec.remove(40, 60);
+        assert_eq!(vec.get_ranges(), &[(0, 40), (60, 100)]);
+
+        vec.remove(40, 60);
+        assert_eq!(vec.get_ranges(), &[(0, 40), (60, 100)]);
+
+        vec.remove(0, 10);
+        assert_eq!(vec.get_ranges(), &[(10, 40), (60, 100)]);
+
+        vec.remove(90, 100);
+        assert_eq!(vec.get_ranges(), &[(10, 40), (60, 90)]);
+
+        vec.remove(-5, 12);
+        assert_eq!(vec.get_ranges(), &[(12, 40), (60, 90)]);
+
+        vec.remove(85, 200);
+        assert_eq!(vec.get_ranges(), &[(12, 40), (60, 85)]);
+
+        vec.remove(30, 70);
+        assert_eq!(vec.get_ranges(), &[(12, 30), (70, 85)]);
+
+        vec.push(40, 50);
+        vec.push(55, 60);
+        assert_eq!(vec.get_ranges(), &[(12, 30), (40, 50), (55, 60), (70, 85)]);
+
+        vec.remove(45, 72);
+        assert_eq!(vec.get_ranges(), &[(12, 30), (40, 45), (72, 85)]);
+
+        vec.remove(30, 40);
+        assert_eq!(vec.get_ranges(), &[(12, 30), (40, 45), (72, 85)]);
+
+        vec.remove(12, 30);
+        assert_eq!(vec.get_ranges(), &[(40, 45), (72, 85)]);
+
+        vec.remove(73, 74);
+        assert_eq!(vec.get_ranges(), &[(40, 45), (72, 73), (74, 85)]);
+
+        vec.push(73, 74);
+        assert_eq!(vec.get_ranges(), &[(40, 45), (72, 85)]);
+
+        vec.remove(-100, 100);
+        assert_eq!(vec.get_ranges(), &[]);
+
+    }
+
+
+    #[test]
+    fn set_operations() {
+
+        let a = RangeVec::from_raw(vec![(0, 10), (20, 30), (40, 50)]).unwrap();
+        let b = RangeVec::from_raw(vec![(5, 20), (25, 26), (28, 45), (60, 70)]).unwrap();
+        let empty = RangeVec::new();
+
+        assert_eq!(a.union(&b).get_ranges(), &[(0, 50), (60, 70)]);
+        assert_eq!(b.union(&a).get_ranges(), &[(0, 50), (60, 70)]);
+        assert_eq!(a.union(&empty).get_ranges(), a.get_ranges());
+        assert_eq!(empty.union(&b).get_ranges(), b.get_ranges());
+
+        assert_eq!(a.intersection(&b).get_ranges(), &[(5, 10), (25, 26), (28, 30), (40, 45)]);
+        assert_eq!(b.intersection(&a).get_ranges(), &[(5, 10), (25, 26), (28, 30), (40, 45)]);
+        assert_eq!(a.intersection(&empty).get_ranges(), &[]);
+
+        assert_eq!(a.difference(&b).get_ranges(), &[(0, 5), (20, 25), (26, 28), (45, 50)]);
+        assert_eq!(b.difference(&a).get_ranges(), &[(10, 20), (30, 40), (60, 70)]);
+        assert_eq!(a.difference(&empty).get_ranges(), a.get_ranges());
+        assert_eq!(empty.difference(&a).get_ranges(), &[]);
+        assert_eq!(a.difference(&a).get_ranges(), &[]);
+
+        assert_eq!(a.complement_within(0, 50).get_ranges(), &[(10, 20), (30, 40)]);
+        assert_eq!(a.complement_within(-5, 55).get_ranges(), &[(-5, 0), (10, 20), (30, 40), (50, 55)]);
+        assert_eq!(a.complement_within(22, 28).get_ranges(), &[]);
+        assert_eq!(b.complement_within(0, 100).get_ranges(), &[(0, 5), (20, 25), (26, 28), (45, 60), (70, 100)]);
+        assert_eq!(empty.complement_within(3, 7).get_ranges(), &[(3, 7)]);
+
+    }
+
+
+    #[test]
+    fn queries() {
+
+        let vec = RangeVec::from_raw(vec![(2, 5), (8, 10), (15, 20)]).unwrap();
+        let empty = RangeVec::<i32>::new();
+
+        assert_eq!(vec.overlapping(0, 2), &[]);
+        assert_eq!(vec.overlapping(0, 3), &[(2, 5)]);
+        assert_eq!(vec.overlapping(5, 8), &[]);
+        assert_eq!(vec.overlapping(4, 9), &[(2, 5), (8, 10)]);
+        assert_eq!(vec.overlapping(9, 100), &[(8, 10), (15, 20)]);
+        assert_eq!(vec.overlapping(20, 100), &[]);
+        assert_eq!(empty.overlapping(0, 10), &[]);
+
+        assert_eq!(vec.gaps(0, 25).collect::<Vec<_>>(), vec![(0, 2), (5, 8), (10, 15), (20, 25)]);
+        assert_eq!(vec.gaps(3, 16).collect::<Vec<_>>(), vec![(5, 8), (10, 15)]);
+        assert_eq!(vec.gaps(5, 8).collect::<Vec<_>>(), vec![(5, 8)]);
+        assert_eq!(vec.gaps(3, 4).collect::<Vec<_>>(), vec![]);
+        assert_eq!(vec.gaps(2, 20).collect::<Vec<_>>(), vec![(5, 8), (10, 15)]);
+        assert_eq!(empty.gaps(3, 7).collect::<Vec<_>>(), vec![(3, 7)]);
+
+        assert_eq!(vec.next_missing_after(0), 0);
+        assert_eq!(vec.next_missing_after(2), 5);
+        assert_eq!(vec.next_missing_after(4), 5);
+        assert_eq!(vec.next_missing_after(5), 5);
+        assert_eq!(vec.next_missing_after(9), 10);
+        assert_eq!(vec.next_missing_after(19), 20);
+        assert_eq!(vec.next_missing_after(30), 30);
+
+        assert_eq!(vec.first_missing(), 0);
+        assert_eq!(RangeVec::from_raw(vec![(0, 5), (8, 10)]).unwrap().first_missing(), 5);
+        assert_eq!(empty.first_missing(), 0);
+
+        assert_eq!(vec.covered_len(), 10);
+        assert_eq!(empty.covered_len(), 0);
+
+    }
+
+
+    #[test]
+    fn varint() {
+
+        fn encode(vec: &RangeVec<u64>) -> Vec<u8> {
+            let mut buf = Vec::new();
+            vec.write_varint(&mut buf).unwrap();
+            buf
+        }
+
+        fn decode(mut buf: &[u8]) -> io::Result<RangeVec<u64>> {
+            let vec = RangeVec::read_varint(&mut buf)?;
+            assert!(buf.is_empty(), "remaining bytes after decoding");
+            Ok(vec)
+        }
+
+        let empty = RangeVec::new();
+        assert_eq!(encode(&empty), &[0]);
+        assert_eq!(decode(&[0]).unwrap().get_ranges(), &[]);
+
+        let vec = RangeVec::from_raw(vec![(0, 1), (2, 130), (1000, 1001), (u64::MAX - 1, u64::MAX)]).unwrap();
+        let buf = encode(&vec);
+        assert_eq!(&buf[..8], &[4, 0, 1, 1, 0x80, 0x01, 0xE6, 0x06]);
+        assert_eq!(decode(&buf).unwrap().get_ranges(), vec.get_ranges());
+
+        // Heavily fragmented ranges should take far less than 16 bytes each.
+        let mut vec = RangeVec::new();
+        for i in 0..1000 {
+            vec.push(i * 3, i * 3 + 2);
+        }
+        let buf = encode(&vec);
+        assert!(buf.len() < 2 * 1000 + 3);
+        assert_eq!(decode(&buf).unwrap().get_ranges(), vec.get_ranges());
+
+        // Empty range.
+        assert_eq!(decode(&[1, 5, 0]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Contiguous ranges.
+        assert_eq!(decode(&[2, 5, 1, 0, 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Overflowing range.
+        assert_eq!(decode(&[1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Overflowing varint.
+        assert_eq!(decode(&[1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02, 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Truncated.
+        assert_eq!(decode(&[2, 5, 1]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decode(&[1, 5]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    }
+
+
+    #[test]
+    fn traits() {
+
+        let vec: RangeVec<u64> = vec![5..8, 0..2, 2..3, 10..10, 7..9].into_iter().collect();
+        assert_eq!(vec.get_ranges(), &[(0, 3), (5, 9)]);
+
+        let other: RangeVec<u64> = vec![(0, 3), (5, 6), (6, 9)].into_iter().collect();
+        assert_eq!(vec, other);
+        assert_eq!(vec.clone(), vec);
+        assert_ne!(vec, RangeVec::default());
+
+        let mut vec = vec;
+        vec.extend([20..25, 9..10]);
+        assert_eq!(vec.get_ranges(), &[(0, 3), (5, 10), (20, 25)]);
+
+        assert_eq!((&vec).into_iter().copied().collect::<Vec<_>>(), vec.get_ranges());
+        assert_eq!(vec.clone().into_iter().collect::<Vec<_>>(), vec.get_ranges());
+
+        let mut vec = RangeVec::<u8>::new();
+        vec.push_range(3..5);
+        vec.push_range(7..=9);
+        vec.push_range((Bound::Excluded(10), Bound::Included(11)));
+        assert_eq!(vec.get_ranges(), &[(3, 5), (7, 10), (11, 12)]);
+        vec.push_range(5..5);
+        assert_eq!(vec.get_ranges(), &[(3, 5), (7, 10), (11, 12)]);
+        vec.push_range(..2);
+        vec.push_range(250..);
+        assert_eq!(vec.get_ranges(), &[(0, 2), (3, 5), (7, 10), (11, 12), (250, 255)]);
+        vec.push_range((Bound::Excluded(u8::MAX), Bound::Unbounded));
+        vec.push_range(240..);
+        assert_eq!(vec.get_ranges(), &[(0, 2), (3, 5), (7, 10), (11, 12), (240, 255)]);
+
+        assert_eq!(vec.iter_values().take(8).collect::<Vec<_>>(), vec![0, 1, 3, 4, 7, 8, 9, 11]);
+        assert_eq!(vec.iter_values().count(), 23);
+        assert_eq!(RangeVec::<i32>::new().iter_values().count(), 0);
+
+    }
+
+
+    #[test]
+    fn bulk() {
+
+        let mut vec = RangeVec::from_raw(vec![(0, 10), (20, 30), (40, 50)]).unwrap();
+
+        vec.extend_sorted([(5, 12), (12, 15), (18, 19), (45, 60), (70, 80)]);
+        assert_eq!(vec.get_ranges(), &[(0, 15), (18, 19), (20, 30), (40, 60), (70, 80)]);
+
+        vec.extend_sorted([]);
+        assert_eq!(vec.get_ranges(), &[(0, 15), (18, 19), (20, 30), (40, 60), (70, 80)]);
+
+        vec.merge(&RangeVec::from_raw(vec![(15, 18), (19, 20), (65, 70)]).unwrap());
+        assert_eq!(vec.get_ranges(), &[(0, 30), (40, 60), (65, 80)]);
+
+        vec.merge(&RangeVec::new());
+        assert_eq!(vec.get_ranges(), &[(0, 30), (40, 60), (65, 80)]);
+
+        let mut vec = RangeVec::new();
+        vec.merge(&RangeVec::from_raw(vec![(1, 2)]).unwrap());
+        assert_eq!(vec.get_ranges(), &[(1, 2)]);
+
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsorted range.")]
+    fn bulk_unsorted() {
+        RangeVec::new().extend_sorted([(5, 6), (1, 2)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Maximum value can't be contained.")]
+    fn push_range_max() {
+        RangeVec::<u8>::new().push_range(u8::MAX..=u8::MAX);
+    }
+
+    #[test]
+    fn bulk_unsorted_unchanged() {
+        let mut vec = RangeVec::from_raw(vec![(0, 10), (20, 30)]).unwrap();
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vec.extend_sorted([(5, 6), (1, 2)]);
+        }));
+        assert!(res.is_err());
+        assert_eq!(vec.get_ranges(), &[(0, 10), (20, 30)]);
+    }
+
+    #[test]
+    fn bulk_large() {
+
+        const COUNT: u64 = 100_000;
+
+        // Every odd range is already present, every even range is added.
+        let base = (0..COUNT).map(|i| (i * 4 + 2, i * 4 + 3)).collect::<RangeVec<_>>();
+        let ranges = (0..COUNT).map(|i| (i * 4, i * 4 + 1)).collect::<Vec<_>>();
+        let expected = RangeVec::from_raw((0..COUNT * 2).map(|i| (i * 2, i * 2 + 1)).collect()).unwrap();
+
+        let mut extended = base.clone();
+        extended.extend_sorted(ranges.iter().copied());
+        assert_eq!(extended, expected);
+
+        let mut pushed = base;
+        for &(from, to) in ranges.iter().step_by(1000).rev() {
+            pushed.push(from, to);
+        }
+        assert_eq!(pushed.get_ranges().len(), COUNT as usize + COUNT as usize / 1000);
+        assert_eq!(pushed.intersection(&extended), pushed);
+
+    }
+
+    /// Run with `cargo test --release -- --ignored bulk_bench --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bulk_bench() {
+
+        use std::time::Instant;
+
+        const COUNT: u64 = 100_000;
+
+        // Every odd range is already present, every even range is pushed in
+        // reverse order, this is the worst case for 'push' because each call
+        // shift the whole vector.
+        let base = (0..COUNT).map(|i| (i * 4 + 2, i * 4 + 3)).collect::<RangeVec<_>>();
+        let ranges = (0..COUNT).map(|i| (i * 4, i * 4 + 1)).collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let mut pushed = base.clone();
+        for &(from, to) in ranges.iter().rev() {
+            pushed.push(from, to);
+        }
+        let push_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        let mut extended = base.clone();
+        extended.extend_sorted(ranges.iter().copied());
+        let extend_elapsed = start.elapsed();
+
+        println!("push: {push_elapsed:?}, extend_sorted: {extend_elapsed:?}");
+        assert_eq!(pushed, extended);
+
+    }
+
+
+    #[test]
+    fn diff() {
+
+        let old = RangeVec::from_raw(vec![(0, 10), (20, 30), (40, 50)]).unwrap();
+        let new = RangeVec::from_raw(vec![(0, 15), (25, 30), (40, 42), (45, 50), (60, 70)]).unwrap();
+
+        let (added, removed) = new.diff(&old);
+        assert_eq!(added.get_ranges(), &[(10, 15), (60, 70)]);
+        assert_eq!(removed.get_ranges(), &[(20, 25), (42, 45)]);
+
+        let mut vec = old.clone();
+        vec.apply_diff(&added, &removed);
+        assert_eq!(vec, new);
+
+        let (added, removed) = old.diff(&new);
+        let mut vec = new.clone();
+        vec.apply_diff(&added, &removed);
+        assert_eq!(vec, old);
+
+        let (added, removed) = new.diff(&new);
+        assert_eq!(added.get_ranges(), &[]);
+        assert_eq!(removed.get_ranges(), &[]);
+
+        let (added, removed) = new.diff(&RangeVec::new());
+        assert_eq!(added, new);
+        assert_eq!(removed.get_ranges(), &[]);
+
+    }
+
+}